@@ -17,7 +17,7 @@ use log::info;
 use pretty_env_logger;
 use tinyp2p::{
     config::P2pConfig, error::P2pSetBootNodeSuccessTypes, Client, DialError, EventHandler,
-    Multiaddr, PeerIdWithMultiaddr, Protocol, ProtocolSupport, ReqRespConfig, Server,
+    Multiaddr, PeerIdWithMultiaddr, PeerInfo, Protocol, ProtocolSupport, ReqRespConfig, Server,
 };
 
 use tinyp2p::protocol::ResponseType;
@@ -165,7 +165,7 @@ impl<D: GuardianDataHandler> Guardian<D> {
         req_resp.support = Option::from(ProtocolSupport::Inbound);
 
         let config = P2pConfig {
-            addr: addr.to_string(),
+            listen_addrs: vec![addr.to_string()],
             pubsub_topics: vec!["notification".to_string()],
             req_resp: Option::from(req_resp),
             boot_node_required,
@@ -277,7 +277,7 @@ impl<D: GuardianDataHandler> EventHandler for GuardianHandler<D> {
 
     fn handle_listener_closed(&self, addrs: Vec<Multiaddr>) {}
 
-    fn handle_identify(&self, peer_id: &tinyp2p::PeerId, addrs: Vec<Multiaddr>) {}
+    fn handle_identify(&self, peer_id: &tinyp2p::PeerId, info: &PeerInfo) {}
 
     fn handle_remove_peer(&self, peer_id: &tinyp2p::PeerId) {}
 
@@ -314,7 +314,7 @@ impl Ward {
         req_resp.support = Option::from(ProtocolSupport::Outbound);
 
         let mut config = P2pConfig {
-            addr: addr.to_string(),
+            listen_addrs: vec![addr.to_string()],
             pubsub_topics: vec![],
             req_resp: Option::from(req_resp),
             ..Default::default()
@@ -406,7 +406,7 @@ impl EventHandler for WardHandler {
 
     fn handle_listener_closed(&self, addrs: Vec<Multiaddr>) {}
 
-    fn handle_identify(&self, peer_id: &libp2p::PeerId, addrs: Vec<Multiaddr>) {}
+    fn handle_identify(&self, peer_id: &libp2p::PeerId, info: &PeerInfo) {}
 
     fn handle_remove_peer(&self, peer_id: &libp2p::PeerId) {}
 
@@ -0,0 +1,331 @@
+//! Bandwidth accounting, per peer at the transport level and per protocol at the application level.
+//!
+//! Every substream of a connection is wrapped to count the bytes it reads and writes.
+//! Once a peer exceeds its quota, its substreams fail and its connections are closed.
+//! The bytes of a protocol or a topic are those of its payloads, before compression and without framing.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use libp2p::{
+    core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox},
+    futures::{AsyncRead, AsyncWrite},
+    PeerId,
+};
+use serde::Serialize;
+
+use crate::config::BandwidthConfig;
+
+/// The bytes received and sent.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Usage {
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+impl Usage {
+    fn add(&mut self, inbound: u64, outbound: u64) {
+        self.inbound = self.inbound.saturating_add(inbound);
+        self.outbound = self.outbound.saturating_add(outbound);
+    }
+}
+
+/// The bandwidth used by the node.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BandwidthReport {
+    /// The bytes over all connections.
+    pub total: Usage,
+    /// The bytes over the connections with each connected peer, and each peer over its quota.
+    pub peers: HashMap<PeerId, Usage>,
+    /// The payload bytes of each request-response protocol, before compression.
+    pub req_resp: HashMap<String, Usage>,
+    /// The payload bytes of each pub/sub topic.
+    pub pubsub: HashMap<String, Usage>,
+}
+
+/// The counters of a peer, shared by its connections so that they are updated without locking.
+#[derive(Debug, Default)]
+struct PeerCounters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+    /// The bytes counted against the quota since it was last reset.
+    quota_used: AtomicU64,
+    quota: Option<u64>,
+    /// The number of open connections with the peer.
+    connections: AtomicUsize,
+}
+
+impl PeerCounters {
+    fn record(&self, inbound: u64, outbound: u64) {
+        self.inbound.fetch_add(inbound, Ordering::Relaxed);
+        self.outbound.fetch_add(outbound, Ordering::Relaxed);
+        self.quota_used.fetch_add(inbound + outbound, Ordering::Relaxed);
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            inbound: self.inbound.load(Ordering::Relaxed),
+            outbound: self.outbound.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_over_quota(&self) -> bool {
+        self.quota
+            .is_some_and(|quota| self.quota_used.load(Ordering::Relaxed) >= quota)
+    }
+
+    /// Whether the peer can be forgotten without losing track of its quota.
+    fn is_idle(&self) -> bool {
+        self.connections.load(Ordering::Relaxed) == 0 && !self.is_over_quota()
+    }
+}
+
+#[derive(Debug, Default)]
+struct MeterState {
+    /// The bytes of the peers which were forgotten.
+    forgotten: Usage,
+    peers: HashMap<PeerId, Arc<PeerCounters>>,
+    req_resp: HashMap<String, Usage>,
+    pubsub: HashMap<String, Usage>,
+}
+
+impl MeterState {
+    fn forget(&mut self, peer: &PeerId) {
+        if let Some(counters) = self.peers.remove(peer) {
+            let usage = counters.usage();
+            self.forgotten.add(usage.inbound, usage.outbound);
+        }
+    }
+}
+
+/// Counts the bytes exchanged with each peer, and enforces their quotas.
+/// The state is only locked when a connection is established or closed, and for the reports.
+#[derive(Debug, Default)]
+pub struct BandwidthMeter {
+    peer_quota: Option<u64>,
+    peer_quotas: HashMap<PeerId, u64>,
+    state: Mutex<MeterState>,
+}
+
+impl BandwidthMeter {
+    pub fn new(config: Option<BandwidthConfig>) -> Self {
+        let config = config.unwrap_or_default();
+        Self {
+            peer_quota: config.peer_quota,
+            peer_quotas: config.peer_quotas,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Reset the bytes counted against the quotas, the totals are kept.
+    /// The peers without connections are forgotten, their bytes only count in the total.
+    pub fn reset_quotas(&self) {
+        let mut state = self.state.lock().unwrap();
+        for counters in state.peers.values() {
+            counters.quota_used.store(0, Ordering::Relaxed);
+        }
+        let idle: Vec<_> = state
+            .peers
+            .iter()
+            .filter(|(_, counters)| counters.is_idle())
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in idle {
+            state.forget(&peer);
+        }
+    }
+
+    /// Record the payload bytes of a request-response protocol, before compression.
+    pub fn record_req_resp(&self, protocol: &str, inbound: u64, outbound: u64) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .req_resp
+            .entry(protocol.to_string())
+            .or_default()
+            .add(inbound, outbound);
+    }
+
+    pub fn record_pubsub(&self, topic: &str, inbound: u64, outbound: u64) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .pubsub
+            .entry(topic.to_string())
+            .or_default()
+            .add(inbound, outbound);
+    }
+
+    /// The bytes over all connections.
+    pub fn total(&self) -> Usage {
+        let state = self.state.lock().unwrap();
+        let mut total = state.forgotten;
+        for counters in state.peers.values() {
+            let usage = counters.usage();
+            total.add(usage.inbound, usage.outbound);
+        }
+        total
+    }
+
+    pub fn report(&self) -> BandwidthReport {
+        let state = self.state.lock().unwrap();
+        let peers: HashMap<_, _> = state
+            .peers
+            .iter()
+            .map(|(peer, counters)| (*peer, counters.usage()))
+            .collect();
+        let mut total = state.forgotten;
+        for usage in peers.values() {
+            total.add(usage.inbound, usage.outbound);
+        }
+        BandwidthReport {
+            total,
+            peers,
+            req_resp: state.req_resp.clone(),
+            pubsub: state.pubsub.clone(),
+        }
+    }
+
+    /// Register a new connection with `peer`, returns the counters of the peer.
+    fn connect(&self, peer: &PeerId) -> Arc<PeerCounters> {
+        let mut state = self.state.lock().unwrap();
+        let counters = state.peers.entry(*peer).or_insert_with(|| {
+            Arc::new(PeerCounters {
+                quota: self.peer_quotas.get(peer).copied().or(self.peer_quota),
+                ..Default::default()
+            })
+        });
+        counters.connections.fetch_add(1, Ordering::Relaxed);
+        counters.clone()
+    }
+
+    /// Unregister a closed connection with `peer`, which is forgotten once it has no connections
+    /// left, unless it exceeded its quota.
+    fn disconnect(&self, peer: &PeerId, counters: &PeerCounters) {
+        let mut state = self.state.lock().unwrap();
+        counters.connections.fetch_sub(1, Ordering::Relaxed);
+        if counters.is_idle() {
+            state.forget(peer);
+        }
+    }
+}
+
+fn quota_exceeded(peer: &PeerId) -> io::Error {
+    io::Error::other(format!("Bandwidth quota of {} exceeded", peer))
+}
+
+/// A muxer counting the bytes of its substreams.
+/// It fails once the peer exceeded its quota, which closes the connection.
+pub struct CountingMuxer {
+    inner: StreamMuxerBox,
+    peer: PeerId,
+    counters: Arc<PeerCounters>,
+    meter: Arc<BandwidthMeter>,
+}
+
+impl CountingMuxer {
+    pub fn new(inner: StreamMuxerBox, peer: PeerId, meter: Arc<BandwidthMeter>) -> Self {
+        let counters = meter.connect(&peer);
+        Self {
+            inner,
+            peer,
+            counters,
+            meter,
+        }
+    }
+
+    fn wrap(&self, substream: Result<SubstreamBox, io::Error>) -> Result<CountingSubstream, io::Error> {
+        if self.counters.is_over_quota() {
+            return Err(quota_exceeded(&self.peer));
+        }
+        substream.map(|inner| CountingSubstream {
+            inner,
+            peer: self.peer,
+            counters: self.counters.clone(),
+        })
+    }
+}
+
+impl Drop for CountingMuxer {
+    fn drop(&mut self) {
+        self.meter.disconnect(&self.peer, &self.counters);
+    }
+}
+
+impl StreamMuxer for CountingMuxer {
+    type Substream = CountingSubstream;
+    type Error = io::Error;
+
+    fn poll_inbound(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let substream = Pin::new(&mut this.inner).poll_inbound(cx);
+        substream.map(|substream| this.wrap(substream))
+    }
+
+    fn poll_outbound(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let substream = Pin::new(&mut this.inner).poll_outbound(cx);
+        substream.map(|substream| this.wrap(substream))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        let this = self.get_mut();
+        if this.counters.is_over_quota() {
+            return Poll::Ready(Err(quota_exceeded(&this.peer)));
+        }
+        Pin::new(&mut this.inner).poll(cx)
+    }
+}
+
+/// A substream counting the bytes it reads and writes.
+pub struct CountingSubstream {
+    inner: SubstreamBox,
+    peer: PeerId,
+    counters: Arc<PeerCounters>,
+}
+
+impl AsyncRead for CountingSubstream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.counters.is_over_quota() {
+            return Poll::Ready(Err(quota_exceeded(&this.peer)));
+        }
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.counters.record(*n as u64, 0);
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for CountingSubstream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.counters.is_over_quota() {
+            return Poll::Ready(Err(quota_exceeded(&this.peer)));
+        }
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.counters.record(0, *n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
@@ -1,64 +1,890 @@
-use std::{fmt, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 
 use libp2p::{
-    identity::{ed25519, Keypair},
+    gossipsub,
+    identity::Keypair,
     multiaddr, Multiaddr, PeerId,
 };
 use libp2p::request_response::ProtocolSupport;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::P2pError,
+    keys, keystore,
+    protocol::{BROADCAST_REPLY_PROTOCOL, KEY_HANDOVER_TOPIC},
+};
 
-use crate::error::P2pError;
+/// The prefix of the environment variables read by `P2pConfig::apply_env`.
+pub const ENV_PREFIX: &str = "TINYP2P_";
 
 /// P2p Configuration.
-#[derive(Clone, Debug, Default)]
+///
+/// It can be loaded from a TOML or JSON file with `P2pConfig::from_file`, where every field is optional,
+/// and overridden by `TINYP2P_*` environment variables with `P2pConfig::apply_env`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct P2pConfig {
-    /// The address to listen on.
-    pub addr: String,
-    /// Secret to generate the local keypair.
+    /// The addresses to listen on, e.g. `/ip4/0.0.0.0/tcp/4001` and `/ip6/::/udp/4001/quic-v1`.
+    /// The single address of the former `addr` field is read too.
+    #[serde(alias = "addr", deserialize_with = "listen_addrs::deserialize")]
+    pub listen_addrs: Vec<String>,
+    /// The type of the local keypair, for `secret` and generated keypairs. Ed25519 by default.
+    pub key_type: KeyType,
+    /// Secret to generate the local keypair, the base58 raw secret key of `key_type`.
     /// If not provided, a random secret will be generated.
+    /// It is never serialized, so that dumping the configuration doesn't leak it.
+    #[serde(skip_serializing)]
     pub secret: Option<String>,
+    /// File of the local keypair, PEM, DER-encoded PKCS#8 or SEC1, or libp2p protobuf-encoded.
+    /// Exclusive with `secret`.
+    pub key_file: Option<PathBuf>,
+    /// Encrypted key file of the local keypair, exclusive with `secret` and `key_file`.
+    /// It is created with a random keypair if missing.
+    pub keystore: Option<KeystoreConfig>,
     /// Bootstrap node to discover the peers in the network.
     /// If not provided, the node will start as a boot node.
     pub boot_node: Option<PeerIdWithMultiaddr>,
+    /// Whether the node must join the network through a boot node,
+    /// which may also be set once the node is running with `Client::set_boot_node`.
     pub boot_node_required: bool,
     /// The interval in seconds to discover the peers in the network.
     pub discovery_interval: Option<u64>,
     /// The topics to subscribe to.
+    /// The `key-handover` topic is reserved for the key handovers of the peers.
     pub pubsub_topics: Vec<String>,
+    /// The topics whose broadcast requests are answered, by subscribing to their companion `<topic>/request` topic.
+    /// Broadcast requests on other topics are not received.
+    pub request_topics: Vec<String>,
+    /// Configuration for the identify protocol.
+    pub identify: Option<IdentifyConfig>,
+    /// Configuration for the external addresses advertised to peers.
+    pub external_addrs: Option<ExternalAddrConfig>,
+    /// Configuration for the pub/sub protocol.
+    pub pubsub: Option<PubsubConfig>,
     /// Configuration for the request-response protocol.
     pub req_resp: Option<ReqRespConfig>,
+    /// Additional named request-response protocols, each with its own configuration.
+    /// The `broadcast-reply` name is reserved for the replies to broadcast requests.
+    pub req_resp_protocols: HashMap<String, ReqRespConfig>,
+    /// Configuration for receiving streams.
+    /// If not provided, inbound streams are refused.
+    pub stream: Option<StreamConfig>,
+    /// The loopback address to serve Prometheus metrics on, e.g. `127.0.0.1:9090`.
+    /// The metrics aren't authenticated, so they are only served locally.
+    /// If not provided, metrics are collected but not served.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Configuration for bandwidth accounting.
+    /// If not provided, bandwidth is accounted without quotas.
+    pub bandwidth: Option<BandwidthConfig>,
+    /// The local endpoint to serve the JSON-RPC control API on.
+    /// If not provided, the node can only be controlled in-process by its `Client`.
+    pub control: Option<ControlEndpoint>,
+    /// The file of the token authenticating the requests to a TCP control endpoint, required for TCP.
+    /// It must only be accessible by its owner, e.g. with mode `0600`.
+    pub control_token_file: Option<PathBuf>,
+    /// The file recording the first key handover of each known peer, to detect conflicting ones across restarts.
+    /// If not provided, they are only recorded in memory.
+    pub key_handovers_file: Option<PathBuf>,
+}
+
+/// The type of a keypair.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    /// ECDSA on the P-256 curve.
+    Ecdsa,
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyType::Ed25519 => f.write_str("ed25519"),
+            KeyType::Secp256k1 => f.write_str("secp256k1"),
+            KeyType::Ecdsa => f.write_str("ecdsa"),
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = P2pError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            _ => Err(invalid_config(format!(
+                "unknown key type `{}`, expected `ed25519`, `secp256k1` or `ecdsa`",
+                s
+            ))),
+        }
+    }
+}
+
+/// Configuration for the encrypted key file of the local keypair.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeystoreConfig {
+    /// The path of the key file.
+    pub path: PathBuf,
+    /// The passphrase of the key file, read from `TINYP2P_KEYSTORE_PASSPHRASE` if not provided.
+    /// It is never serialized.
+    #[serde(skip_serializing)]
+    pub passphrase: Option<String>,
+}
+
+impl KeystoreConfig {
+    /// Returns the configured passphrase, or the one from the environment.
+    pub fn passphrase(&self) -> Result<String, P2pError> {
+        match &self.passphrase {
+            Some(passphrase) => Ok(passphrase.clone()),
+            None => env::var(keystore::PASSPHRASE_ENV).map_err(|_| {
+                P2pError::KeystoreError(format!(
+                    "no passphrase configured for {}, set {}",
+                    self.path.display(),
+                    keystore::PASSPHRASE_ENV
+                ))
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for KeystoreConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeystoreConfig")
+            .field("path", &self.path)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// The endpoint of the control API, parsed from `unix:<path>` or `<ip>:<port>`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlEndpoint {
+    /// A Unix socket, only accessible by the owner of the node.
+    Unix(PathBuf),
+    /// A TCP port on a loopback address.
+    Tcp(SocketAddr),
+}
+
+impl fmt::Display for ControlEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            ControlEndpoint::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl FromStr for ControlEndpoint {
+    type Err = P2pError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ControlEndpoint::Unix(path.into())),
+            None => s
+                .parse()
+                .map(ControlEndpoint::Tcp)
+                .map_err(|err| invalid_config(format!("invalid control endpoint `{}`: {}", s, err))),
+        }
+    }
+}
+
+/// Configuration for the identify protocol.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdentifyConfig {
+    /// The protocol version sent to peers, `/cyber-guardians/identify/1.0.0` by default.
+    /// Peers with another protocol version still connect, applications may check it.
+    pub protocol_version: Option<String>,
+    /// The agent version sent to peers, `tinyp2p/<version>` by default.
+    pub agent_version: Option<String>,
+    /// Whether to push identify updates to peers when the listen addresses change, true by default.
+    pub push_updates: Option<bool>,
+    /// Interval in seconds of the periodic identify requests, 5 minutes by default.
+    pub interval: Option<u64>,
+}
+
+/// Configuration for the external addresses, the addresses advertised to peers.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExternalAddrConfig {
+    /// Public addresses of the node, e.g. of a port forwarding, always advertised.
+    pub public_addrs: Vec<Multiaddr>,
+    /// The number of distinct peers which must observe an address before it is advertised, 2 by default.
+    pub confirmations: Option<usize>,
+    /// Seconds an observed address stays advertised without being observed again, 30 minutes by default.
+    pub observed_ttl: Option<u64>,
+    /// Whether to advertise the listen addresses on public interfaces, true by default.
+    pub advertise_listen_addrs: Option<bool>,
+}
+
+/// Configuration for bandwidth accounting.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Maximum bytes exchanged with a single peer, further traffic with it is rejected
+    /// until the quotas are reset.
+    pub peer_quota: Option<u64>,
+    /// Quotas of specific peers, overriding `peer_quota`.
+    pub peer_quotas: HashMap<PeerId, u64>,
+}
+
+/// Configuration for receiving streams.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamConfig {
+    /// The directory where received streams are stored, named by their ID.
+    pub dir: PathBuf,
+    /// Maximum size of an inbound stream in bytes, 256 MiB by default.
+    pub max_stream_size: Option<u64>,
+    /// Maximum number of inbound streams being received from a single peer, 4 by default.
+    pub max_streams_per_peer: Option<usize>,
+    /// Maximum total size in bytes of the inbound streams being received from a single peer, 512 MiB by default.
+    pub max_pending_bytes_per_peer: Option<u64>,
+    /// Seconds after which an idle partial stream is dropped with its partial file, 24 hours by default.
+    pub partial_ttl: Option<u64>,
 }
 
 /// Configuration for the request-response protocol.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ReqRespConfig {
+    /// One of `inbound`, `outbound` or `full`, the default.
+    #[serde(with = "protocol_support")]
     pub support: Option<ProtocolSupport>,
     /// Connection keep-alive time in seconds.
     pub connection_keep_alive: Option<u64>,
     /// Request timeout in seconds.
     pub request_timeout: Option<u64>,
-    /// Maximum size of an inbound request.
+    /// Maximum size of an inbound request, 1 MiB by default.
     pub max_request_size: Option<usize>,
-    /// Maximum size of an inbound response.
+    /// Maximum size of an inbound response, 1 MiB by default.
     pub max_response_size: Option<usize>,
+    /// Rate limit of inbound requests from a single peer.
+    pub peer_rate_limit: Option<RateLimit>,
+    /// Rate limit of inbound requests from all peers.
+    pub global_rate_limit: Option<RateLimit>,
+    /// Compression of outbound payloads, peers which don't support it get them uncompressed.
+    pub compression: Option<Compression>,
+    /// Minimum size of a payload to compress, 1 KiB by default.
+    pub compression_threshold: Option<usize>,
+}
+
+/// Compression algorithm of request-response payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Deflate,
+    Zstd,
+}
+
+/// Token-bucket rate limit.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Maximum number of requests accepted in a burst.
+    pub burst: u32,
+    /// Number of requests replenished per second.
+    pub per_second: u32,
+}
+
+/// Configuration for the pub/sub protocol.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PubsubConfig {
+    /// Target number of peers in the mesh of a topic (D).
+    pub mesh_n: Option<usize>,
+    /// Minimum number of peers in the mesh of a topic (D_lo).
+    pub mesh_n_low: Option<usize>,
+    /// Maximum number of peers in the mesh of a topic (D_hi).
+    pub mesh_n_high: Option<usize>,
+    /// Heartbeat interval in seconds.
+    pub heartbeat_interval: Option<u64>,
+    /// Number of heartbeats a message is kept in the message cache.
+    pub history_length: Option<usize>,
+    /// Whether to publish own messages to all peers of a topic instead of the mesh only.
+    pub flood_publish: Option<bool>,
+    /// Maximum size of a message.
+    pub max_transmit_size: Option<usize>,
+    /// How message IDs are computed, defaults to `MessageIdStrategy::SourceSequence`.
+    pub message_id: Option<MessageIdStrategy>,
+    /// Peer scoring, disabled if not provided.
+    pub peer_score: Option<PeerScoreConfig>,
+    /// The peers allowed to publish on a topic, keyed by topic name.
+    /// Anyone may publish on topics which are not listed.
+    pub topic_acls: HashMap<String, TopicAcl>,
+}
+
+/// The access control list of a topic, checked against the signed source of inbound messages.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicAcl {
+    /// Only the listed peers may publish.
+    Peers(HashSet<PeerId>),
+    /// A user-supplied predicate on the source peer, it can't be (de)serialized.
+    #[serde(skip)]
+    Custom(Arc<dyn Fn(&PeerId) -> bool + Send + Sync>),
+}
+
+impl TopicAcl {
+    /// Whether `source` is allowed to publish.
+    pub fn is_allowed(&self, source: &PeerId) -> bool {
+        match self {
+            TopicAcl::Peers(peers) => peers.contains(source),
+            TopicAcl::Custom(f) => f(source),
+        }
+    }
+}
+
+impl fmt::Debug for TopicAcl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TopicAcl::Peers(peers) => f.debug_tuple("Peers").field(peers).finish(),
+            TopicAcl::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// The strategy to compute pub/sub message IDs.
+/// Messages with the same ID are considered duplicates and delivered only once.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageIdStrategy {
+    /// Hash of the source peer and its sequence number, so every published message is unique.
+    #[default]
+    SourceSequence,
+    /// SHA-256 of the message data, so identical messages are delivered only once.
+    Sha256,
+    /// A user-supplied function, it can't be (de)serialized.
+    #[serde(skip)]
+    Custom(Arc<dyn Fn(&gossipsub::Message) -> gossipsub::MessageId + Send + Sync>),
+}
+
+impl MessageIdStrategy {
+    /// Compute the ID of the given message.
+    pub fn message_id(&self, message: &gossipsub::Message) -> gossipsub::MessageId {
+        match self {
+            MessageIdStrategy::SourceSequence => {
+                let mut hasher = Sha256::new();
+                match (message.source, message.sequence_number) {
+                    (Some(source), Some(seqno)) => {
+                        hasher.update(source.to_bytes());
+                        hasher.update(seqno.to_be_bytes());
+                    }
+                    // Unsigned messages carry neither, fall back to the content.
+                    _ => hasher.update(&message.data),
+                }
+                gossipsub::MessageId::from(hasher.finalize().to_vec())
+            }
+            MessageIdStrategy::Sha256 => {
+                gossipsub::MessageId::from(Sha256::digest(&message.data).to_vec())
+            }
+            MessageIdStrategy::Custom(f) => f(message),
+        }
+    }
+}
+
+impl fmt::Debug for MessageIdStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageIdStrategy::SourceSequence => f.write_str("SourceSequence"),
+            MessageIdStrategy::Sha256 => f.write_str("Sha256"),
+            MessageIdStrategy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Configuration for pub/sub peer scoring.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerScoreConfig {
+    pub thresholds: PeerScoreThresholds,
+    /// Score parameters of each topic, keyed by topic name.
+    pub topics: HashMap<String, TopicScoreParams>,
+}
+
+/// The score thresholds below which peers are penalized, see `gossipsub::PeerScoreThresholds`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerScoreThresholds {
+    /// No gossip is exchanged with peers below this score.
+    pub gossip_threshold: f64,
+    /// Own messages are not published to peers below this score.
+    pub publish_threshold: f64,
+    /// All messages from peers below this score are ignored.
+    pub graylist_threshold: f64,
+    /// Peer exchange is only accepted from peers above this score.
+    pub accept_px_threshold: f64,
+    /// Median mesh score below which opportunistic grafting is triggered.
+    pub opportunistic_graft_threshold: f64,
+}
+
+impl Default for PeerScoreThresholds {
+    fn default() -> Self {
+        gossipsub::PeerScoreThresholds::default().into()
+    }
+}
+
+impl From<gossipsub::PeerScoreThresholds> for PeerScoreThresholds {
+    fn from(t: gossipsub::PeerScoreThresholds) -> Self {
+        Self {
+            gossip_threshold: t.gossip_threshold,
+            publish_threshold: t.publish_threshold,
+            graylist_threshold: t.graylist_threshold,
+            accept_px_threshold: t.accept_px_threshold,
+            opportunistic_graft_threshold: t.opportunistic_graft_threshold,
+        }
+    }
+}
+
+impl From<PeerScoreThresholds> for gossipsub::PeerScoreThresholds {
+    fn from(t: PeerScoreThresholds) -> Self {
+        Self {
+            gossip_threshold: t.gossip_threshold,
+            publish_threshold: t.publish_threshold,
+            graylist_threshold: t.graylist_threshold,
+            accept_px_threshold: t.accept_px_threshold,
+            opportunistic_graft_threshold: t.opportunistic_graft_threshold,
+        }
+    }
+}
+
+/// The score parameters of a topic, see `gossipsub::TopicScoreParams`.
+/// Parameters which are not listed here keep their gossipsub defaults.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicScoreParams {
+    /// Weight of the topic in the overall score of a peer.
+    pub topic_weight: f64,
+    /// Reward for the time a peer has been in the mesh.
+    pub time_in_mesh_weight: f64,
+    pub time_in_mesh_cap: f64,
+    /// Reward for being the first to deliver a message.
+    pub first_message_deliveries_weight: f64,
+    pub first_message_deliveries_cap: f64,
+    /// Penalty for delivering too few messages while in the mesh.
+    pub mesh_message_deliveries_weight: f64,
+    /// Penalty for delivering messages which failed validation.
+    pub invalid_message_deliveries_weight: f64,
+    pub invalid_message_deliveries_decay: f64,
+}
+
+impl Default for TopicScoreParams {
+    fn default() -> Self {
+        let p = gossipsub::TopicScoreParams::default();
+        Self {
+            topic_weight: p.topic_weight,
+            time_in_mesh_weight: p.time_in_mesh_weight,
+            time_in_mesh_cap: p.time_in_mesh_cap,
+            first_message_deliveries_weight: p.first_message_deliveries_weight,
+            first_message_deliveries_cap: p.first_message_deliveries_cap,
+            mesh_message_deliveries_weight: p.mesh_message_deliveries_weight,
+            invalid_message_deliveries_weight: p.invalid_message_deliveries_weight,
+            invalid_message_deliveries_decay: p.invalid_message_deliveries_decay,
+        }
+    }
+}
+
+impl From<TopicScoreParams> for gossipsub::TopicScoreParams {
+    fn from(p: TopicScoreParams) -> Self {
+        Self {
+            topic_weight: p.topic_weight,
+            time_in_mesh_weight: p.time_in_mesh_weight,
+            time_in_mesh_cap: p.time_in_mesh_cap,
+            first_message_deliveries_weight: p.first_message_deliveries_weight,
+            first_message_deliveries_cap: p.first_message_deliveries_cap,
+            mesh_message_deliveries_weight: p.mesh_message_deliveries_weight,
+            invalid_message_deliveries_weight: p.invalid_message_deliveries_weight,
+            invalid_message_deliveries_decay: p.invalid_message_deliveries_decay,
+            ..Default::default()
+        }
+    }
+}
+
+/// (De)serialize the request-response protocol support as `inbound`, `outbound` or `full`.
+mod protocol_support {
+    use libp2p::request_response::ProtocolSupport;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(support: &Option<ProtocolSupport>, serializer: S) -> Result<S::Ok, S::Error> {
+        match support {
+            Some(ProtocolSupport::Inbound) => serializer.serialize_some("inbound"),
+            Some(ProtocolSupport::Outbound) => serializer.serialize_some("outbound"),
+            Some(ProtocolSupport::Full) => serializer.serialize_some("full"),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ProtocolSupport>, D::Error> {
+        match Option::<String>::deserialize(deserializer)?.as_deref() {
+            Some("inbound") => Ok(Some(ProtocolSupport::Inbound)),
+            Some("outbound") => Ok(Some(ProtocolSupport::Outbound)),
+            Some("full") => Ok(Some(ProtocolSupport::Full)),
+            Some(other) => Err(D::Error::custom(format!(
+                "unknown protocol support `{}`, expected `inbound`, `outbound` or `full`",
+                other
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Deserialize the listen addresses from a list, or from the single address of the former `addr` field.
+mod listen_addrs {
+    use serde::{Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ListenAddrs {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        Ok(match ListenAddrs::deserialize(deserializer)? {
+            ListenAddrs::One(addr) => vec![addr],
+            ListenAddrs::Many(addrs) => addrs,
+        })
+    }
 }
 
 impl P2pConfig {
-    /// Generate a keypair from the secret.
+    /// Load the configuration from a TOML or JSON file, according to its extension.
+    /// The loaded configuration is not validated, see `P2pConfig::load`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, P2pError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&content),
+            Some("json") => Self::from_json(&content),
+            _ => Err(P2pError::InvalidConfig(format!(
+                "unsupported config file {}, expected a .toml or .json file",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, P2pError> {
+        toml::from_str(content).map_err(|err| P2pError::InvalidConfig(err.to_string()))
+    }
+
+    pub fn from_json(content: &str) -> Result<Self, P2pError> {
+        serde_json::from_str(content).map_err(|err| P2pError::InvalidConfig(err.to_string()))
+    }
+
+    /// Load the configuration from an optional file, override it with the environment variables,
+    /// and validate the result.
+    pub fn load(path: Option<&Path>) -> Result<Self, P2pError> {
+        let config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        let config = config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override the configuration with the environment variables which are set:
+    ///
+    /// - `TINYP2P_LISTEN_ADDRS`, comma-separated
+    /// - `TINYP2P_ADDR`, a single listen address, if `TINYP2P_LISTEN_ADDRS` isn't set
+    /// - `TINYP2P_KEY_TYPE`
+    /// - `TINYP2P_SECRET`
+    /// - `TINYP2P_KEY_FILE`
+    /// - `TINYP2P_KEYSTORE`, the path of the key file, its passphrase is read from `TINYP2P_KEYSTORE_PASSPHRASE`
+    /// - `TINYP2P_BOOT_NODE`
+    /// - `TINYP2P_BOOT_NODE_REQUIRED`
+    /// - `TINYP2P_DISCOVERY_INTERVAL`
+    /// - `TINYP2P_PUBSUB_TOPICS`, comma-separated
+    /// - `TINYP2P_REQUEST_TOPICS`, comma-separated
+    /// - `TINYP2P_PUBLIC_ADDRS`, comma-separated
+    /// - `TINYP2P_METRICS_ADDR`
+    /// - `TINYP2P_CONTROL`, `unix:<path>` or `<ip>:<port>`
+    /// - `TINYP2P_CONTROL_TOKEN_FILE`
+    /// - `TINYP2P_KEY_HANDOVERS_FILE`
+    pub fn apply_env(mut self) -> Result<Self, P2pError> {
+        if let Some(addrs) = env_var::<String>("LISTEN_ADDRS")? {
+            self.listen_addrs = addrs
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .map(String::from)
+                .collect();
+        } else if let Some(addr) = env_var("ADDR")? {
+            self.listen_addrs = vec![addr];
+        }
+        if let Some(key_type) = env_var("KEY_TYPE")? {
+            self.key_type = key_type;
+        }
+        if let Some(secret) = env_var("SECRET")? {
+            self.secret = Some(secret);
+        }
+        if let Some(path) = env_var("KEY_FILE")? {
+            self.key_file = Some(path);
+        }
+        if let Some(path) = env_var("KEYSTORE")? {
+            self.keystore.get_or_insert_with(KeystoreConfig::default).path = path;
+        }
+        if let Some(boot_node) = env_var("BOOT_NODE")? {
+            self.boot_node = Some(boot_node);
+        }
+        if let Some(required) = env_var("BOOT_NODE_REQUIRED")? {
+            self.boot_node_required = required;
+        }
+        if let Some(interval) = env_var("DISCOVERY_INTERVAL")? {
+            self.discovery_interval = Some(interval);
+        }
+        if let Some(topics) = env_var::<String>("PUBSUB_TOPICS")? {
+            self.pubsub_topics = topics
+                .split(',')
+                .map(str::trim)
+                .filter(|topic| !topic.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(topics) = env_var::<String>("REQUEST_TOPICS")? {
+            self.request_topics = topics
+                .split(',')
+                .map(str::trim)
+                .filter(|topic| !topic.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(addrs) = env_var::<String>("PUBLIC_ADDRS")? {
+            self.external_addrs.get_or_insert_with(ExternalAddrConfig::default).public_addrs = addrs
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .map(|addr| {
+                    addr.parse()
+                        .map_err(|err| invalid_config(format!("invalid public address `{}`: {}", addr, err)))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(addr) = env_var("METRICS_ADDR")? {
+            self.metrics_addr = Some(addr);
+        }
+        if let Some(endpoint) = env_var("CONTROL")? {
+            self.control = Some(endpoint);
+        }
+        if let Some(path) = env_var("CONTROL_TOKEN_FILE")? {
+            self.control_token_file = Some(path);
+        }
+        if let Some(path) = env_var("KEY_HANDOVERS_FILE")? {
+            self.key_handovers_file = Some(path);
+        }
+        Ok(self)
+    }
+
+    /// Check the configuration for mistakes which would otherwise only show up at runtime.
+    pub fn validate(&self) -> Result<(), P2pError> {
+        if self.listen_addrs.is_empty() {
+            return Err(invalid_config("at least one listen address must be configured"));
+        }
+        for addr in &self.listen_addrs {
+            addr.parse::<Multiaddr>()
+                .map_err(|err| invalid_config(format!("invalid listen address `{}`: {}", addr, err)))?;
+        }
+        let sources = [self.secret.is_some(), self.key_file.is_some(), self.keystore.is_some()];
+        if sources.iter().filter(|set| **set).count() > 1 {
+            return Err(invalid_config("`secret`, `key_file` and `keystore` are exclusive"));
+        }
+        if self.secret.is_some() || self.key_file.is_some() {
+            self.gen_keypair()?;
+        }
+        if let Some(keystore) = &self.keystore {
+            if keystore.path.as_os_str().is_empty() {
+                return Err(invalid_config("the keystore path must be set"));
+            }
+            keystore.passphrase()?;
+        }
+        if self.discovery_interval == Some(0) {
+            return Err(invalid_config("the discovery interval must be positive"));
+        }
+        if let Some(identify) = &self.identify {
+            if identify.interval == Some(0) {
+                return Err(invalid_config("the identify interval must be positive"));
+            }
+            if identify.protocol_version.as_deref() == Some("") {
+                return Err(invalid_config("the identify protocol version must not be empty"));
+            }
+        }
+        if let Some(external_addrs) = &self.external_addrs {
+            if external_addrs.confirmations == Some(0) {
+                return Err(invalid_config("the external address confirmations must be positive"));
+            }
+            if external_addrs.observed_ttl == Some(0) {
+                return Err(invalid_config("the observed address TTL must be positive"));
+            }
+        }
+        if self
+            .pubsub_topics
+            .iter()
+            .chain(&self.request_topics)
+            .any(|topic| topic == KEY_HANDOVER_TOPIC)
+        {
+            return Err(invalid_config(format!("the `{}` topic is reserved", KEY_HANDOVER_TOPIC)));
+        }
+        if let Some(pubsub) = &self.pubsub {
+            pubsub.validate()?;
+        }
+        if let Some(req_resp) = &self.req_resp {
+            req_resp.validate("default")?;
+        }
+        for (name, req_resp) in &self.req_resp_protocols {
+            if name == BROADCAST_REPLY_PROTOCOL {
+                return Err(invalid_config(format!("the `{}` protocol is reserved", name)));
+            }
+            req_resp.validate(name)?;
+        }
+        if let Some(stream) = &self.stream {
+            if stream.dir.as_os_str().is_empty() {
+                return Err(invalid_config("the stream directory must be set"));
+            }
+            if stream.max_streams_per_peer == Some(0)
+                || stream.max_pending_bytes_per_peer == Some(0)
+                || stream.partial_ttl == Some(0)
+            {
+                return Err(invalid_config("the stream limits must be positive"));
+            }
+        }
+        match &self.control {
+            Some(ControlEndpoint::Tcp(addr)) if !addr.ip().is_loopback() => {
+                return Err(invalid_config(format!(
+                    "the control API must be bound to a loopback address, not {}",
+                    addr
+                )));
+            }
+            Some(ControlEndpoint::Tcp(_)) if self.control_token_file.is_none() => {
+                return Err(invalid_config("the TCP control API requires a token file"));
+            }
+            _ => {}
+        }
+        match self.metrics_addr {
+            Some(addr) if !addr.ip().is_loopback() => {
+                return Err(invalid_config(format!(
+                    "the metrics must be served on a loopback address, not {}",
+                    addr
+                )));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Dump the configuration as TOML.
+    /// Fails if it contains custom topic ACLs or message ID functions, which can't be serialized.
+    pub fn to_toml(&self) -> Result<String, P2pError> {
+        toml::to_string_pretty(self).map_err(|err| P2pError::InvalidConfig(err.to_string()))
+    }
+
+    /// Dump the configuration as JSON.
+    /// Fails if it contains custom topic ACLs or message ID functions, which can't be serialized.
+    pub fn to_json(&self) -> Result<String, P2pError> {
+        serde_json::to_string_pretty(self).map_err(|err| P2pError::InvalidConfig(err.to_string()))
+    }
+
+    /// Generate a keypair from the secret or the key file, or load it from the keystore.
+    /// A missing keystore is created with a random keypair of `key_type`.
     pub fn gen_keypair(&self) -> Result<Keypair, P2pError> {
-        let secret = match &self.secret {
-            Some(secret) => {
-                let decoded = bs58::decode(secret)
-                    .into_vec()
-                    .map_err(|err| P2pError::InvalidSecretKey(err.to_string()))?;
-
-                ed25519::SecretKey::try_from_bytes(decoded)
-                    .map_err(|err| P2pError::InvalidSecretKey(err.to_string()))?
+        if let Some(config) = &self.keystore {
+            let passphrase = config.passphrase()?;
+            return keystore::load_or_create_keypair(&config.path, &passphrase, || {
+                Ok(keys::generate(self.key_type))
+            });
+        }
+        if self.key_file.is_none() && self.secret.is_none() {
+            return Ok(keys::generate(self.key_type));
+        }
+        self.load_keypair()
+    }
+
+    /// Load the configured keypair, without generating one nor creating a missing key file.
+    pub fn load_keypair(&self) -> Result<Keypair, P2pError> {
+        if let Some(config) = &self.keystore {
+            return keystore::load_keypair(&config.path, &config.passphrase()?);
+        }
+
+        if let Some(path) = &self.key_file {
+            return keys::decode_key_file(&fs::read(path)?);
+        }
+
+        match &self.secret {
+            Some(secret) => keys::decode_secret(self.key_type, secret),
+            None => Err(invalid_config("no secret, key file or keystore configured")),
+        }
+    }
+}
+
+impl ReqRespConfig {
+    fn validate(&self, name: &str) -> Result<(), P2pError> {
+        if self.request_timeout == Some(0) {
+            return Err(invalid_config(format!("protocol `{}`: the request timeout must be positive", name)));
+        }
+        if self.max_request_size == Some(0) || self.max_response_size == Some(0) {
+            return Err(invalid_config(format!("protocol `{}`: the maximum message sizes must be positive", name)));
+        }
+        for limit in self.peer_rate_limit.iter().chain(&self.global_rate_limit) {
+            if limit.burst == 0 || limit.per_second == 0 {
+                return Err(invalid_config(format!("protocol `{}`: rate limits must be positive", name)));
             }
-            None => ed25519::SecretKey::generate(),
-        };
+        }
+        Ok(())
+    }
+}
+
+impl PubsubConfig {
+    fn validate(&self) -> Result<(), P2pError> {
+        // Unset sizes keep the gossipsub defaults.
+        let mesh_n = self.mesh_n.unwrap_or(6);
+        let mesh_n_low = self.mesh_n_low.unwrap_or(5);
+        let mesh_n_high = self.mesh_n_high.unwrap_or(12);
+        if !(mesh_n_low <= mesh_n && mesh_n <= mesh_n_high) {
+            return Err(invalid_config(format!(
+                "pub/sub mesh sizes must satisfy mesh_n_low ({}) <= mesh_n ({}) <= mesh_n_high ({})",
+                mesh_n_low, mesh_n, mesh_n_high
+            )));
+        }
+        if self.heartbeat_interval == Some(0) {
+            return Err(invalid_config("the pub/sub heartbeat interval must be positive"));
+        }
+        if self.max_transmit_size == Some(0) {
+            return Err(invalid_config("the pub/sub maximum message size must be positive"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid_config(reason: impl Into<String>) -> P2pError {
+    P2pError::InvalidConfig(reason.into())
+}
 
-        Ok(ed25519::Keypair::from(secret).into())
+/// Read and parse the environment variable `TINYP2P_<name>`, if set.
+fn env_var<T>(name: &str) -> Result<Option<T>, P2pError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+{
+    let key = format!("{}{}", ENV_PREFIX, name);
+    match env::var(&key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| invalid_config(format!("{}: {}", key, err))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(invalid_config(format!("{}: {}", key, err))),
     }
 }
 
@@ -130,4 +956,266 @@ fn parse_str_addr(addr_str: &str) -> Result<(PeerId, Multiaddr), P2pError> {
     };
 
     Ok((peer_id, addr))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::keys::KeyFormat;
+
+    /// The environment is shared by the tests, which run in parallel.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const PEER_ID: &str = "12D3KooWSoC2ngFnfgSZcyJibKmZ2G58kbFcpmSPSSvDxeqkBLJc";
+
+    /// Apply the `TINYP2P_<name>` variables to a default configuration, they are unset afterwards.
+    fn apply_env(vars: &[(&str, &str)]) -> Result<P2pConfig, P2pError> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        for (name, value) in vars {
+            env::set_var(format!("{}{}", ENV_PREFIX, name), value);
+        }
+        let config = P2pConfig::default().apply_env();
+        for (name, _) in vars {
+            env::remove_var(format!("{}{}", ENV_PREFIX, name));
+        }
+        config
+    }
+
+    fn valid() -> P2pConfig {
+        P2pConfig {
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/0".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn assert_invalid(config: P2pConfig, reason: &str) {
+        match config.validate() {
+            Err(P2pError::InvalidConfig(err)) => assert!(err.contains(reason), "`{}` doesn't contain `{}`", err, reason),
+            other => panic!("expected `{}`, got {:?}", reason, other),
+        }
+    }
+
+    #[test]
+    fn apply_env_parses_variables() {
+        let config = apply_env(&[
+            ("LISTEN_ADDRS", " /ip4/127.0.0.1/tcp/1 , ,/ip4/127.0.0.1/udp/1/quic-v1"),
+            ("KEY_TYPE", "secp256k1"),
+            ("SECRET", "secret"),
+            ("KEY_FILE", "/keys/node.pem"),
+            ("KEYSTORE", "/keys/node.keystore"),
+            ("BOOT_NODE", &format!("/ip4/127.0.0.1/tcp/2/p2p/{}", PEER_ID)),
+            ("BOOT_NODE_REQUIRED", "true"),
+            ("DISCOVERY_INTERVAL", "30"),
+            ("PUBSUB_TOPICS", "alerts, news,"),
+            ("REQUEST_TOPICS", "alerts"),
+            ("PUBLIC_ADDRS", "/ip4/203.0.113.1/tcp/1,/ip4/203.0.113.2/tcp/1"),
+            ("METRICS_ADDR", "127.0.0.1:9090"),
+            ("CONTROL", "unix:/run/tinyp2p.sock"),
+            ("CONTROL_TOKEN_FILE", "/run/tinyp2p.token"),
+            ("KEY_HANDOVERS_FILE", "/var/lib/tinyp2p/handovers"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.listen_addrs, ["/ip4/127.0.0.1/tcp/1", "/ip4/127.0.0.1/udp/1/quic-v1"]);
+        assert_eq!(config.key_type, KeyType::Secp256k1);
+        assert_eq!(config.secret.as_deref(), Some("secret"));
+        assert_eq!(config.key_file, Some(PathBuf::from("/keys/node.pem")));
+        assert_eq!(config.keystore.unwrap().path, PathBuf::from("/keys/node.keystore"));
+        assert_eq!(config.boot_node.unwrap().peer_id().to_base58(), PEER_ID);
+        assert!(config.boot_node_required);
+        assert_eq!(config.discovery_interval, Some(30));
+        assert_eq!(config.pubsub_topics, ["alerts", "news"]);
+        assert_eq!(config.request_topics, ["alerts"]);
+        assert_eq!(config.external_addrs.unwrap().public_addrs.len(), 2);
+        assert_eq!(config.metrics_addr, Some("127.0.0.1:9090".parse().unwrap()));
+        assert_eq!(config.control, Some(ControlEndpoint::Unix("/run/tinyp2p.sock".into())));
+        assert_eq!(config.control_token_file, Some(PathBuf::from("/run/tinyp2p.token")));
+        assert_eq!(config.key_handovers_file, Some(PathBuf::from("/var/lib/tinyp2p/handovers")));
+    }
+
+    #[test]
+    fn apply_env_reads_the_former_addr() {
+        let config = apply_env(&[("ADDR", "/ip4/127.0.0.1/tcp/1")]).unwrap();
+        assert_eq!(config.listen_addrs, ["/ip4/127.0.0.1/tcp/1"]);
+
+        let config = apply_env(&[("ADDR", "/ip4/127.0.0.1/tcp/1"), ("LISTEN_ADDRS", "/ip4/127.0.0.1/tcp/2")]).unwrap();
+        assert_eq!(config.listen_addrs, ["/ip4/127.0.0.1/tcp/2"]);
+    }
+
+    #[test]
+    fn apply_env_reports_the_invalid_variable() {
+        let cases = [
+            ("DISCOVERY_INTERVAL", "soon", "TINYP2P_DISCOVERY_INTERVAL"),
+            ("KEY_TYPE", "rsa", "unknown key type `rsa`"),
+            ("PUBLIC_ADDRS", "/ip4/203.0.113.1/tcp/1,nope", "invalid public address `nope`"),
+            ("CONTROL", "localhost", "invalid control endpoint `localhost`"),
+        ];
+        for (name, value, reason) in cases {
+            match apply_env(&[(name, value)]) {
+                Err(P2pError::InvalidConfig(err)) => assert!(err.contains(reason), "`{}` doesn't contain `{}`", err, reason),
+                other => panic!("expected `{}`, got {:?}", reason, other),
+            }
+        }
+    }
+
+    #[test]
+    fn listen_addrs_accept_the_former_addr() {
+        let config = P2pConfig::from_toml(r#"addr = "/ip4/127.0.0.1/tcp/1""#).unwrap();
+        assert_eq!(config.listen_addrs, ["/ip4/127.0.0.1/tcp/1"]);
+        let config = P2pConfig::from_json(r#"{"addr": "/ip4/127.0.0.1/tcp/1"}"#).unwrap();
+        assert_eq!(config.listen_addrs, ["/ip4/127.0.0.1/tcp/1"]);
+        let config = P2pConfig::from_toml(r#"listen_addrs = ["/ip4/127.0.0.1/tcp/1", "/ip4/127.0.0.1/tcp/2"]"#).unwrap();
+        assert_eq!(config.listen_addrs, ["/ip4/127.0.0.1/tcp/1", "/ip4/127.0.0.1/tcp/2"]);
+    }
+
+    #[test]
+    fn validate_accepts_a_required_boot_node_set_later() {
+        P2pConfig {
+            boot_node_required: true,
+            ..valid()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_mistakes() {
+        assert_invalid(P2pConfig::default(), "at least one listen address");
+        assert_invalid(
+            P2pConfig {
+                listen_addrs: vec!["nope".to_string()],
+                ..valid()
+            },
+            "invalid listen address `nope`",
+        );
+        assert_invalid(
+            P2pConfig {
+                secret: Some(String::new()),
+                key_file: Some("/keys/node.pem".into()),
+                ..valid()
+            },
+            "are exclusive",
+        );
+        assert_invalid(
+            P2pConfig {
+                keystore: Some(KeystoreConfig::default()),
+                ..valid()
+            },
+            "the keystore path must be set",
+        );
+        assert_invalid(
+            P2pConfig {
+                discovery_interval: Some(0),
+                ..valid()
+            },
+            "the discovery interval must be positive",
+        );
+        assert_invalid(
+            P2pConfig {
+                identify: Some(IdentifyConfig {
+                    protocol_version: Some(String::new()),
+                    ..Default::default()
+                }),
+                ..valid()
+            },
+            "the identify protocol version must not be empty",
+        );
+        assert_invalid(
+            P2pConfig {
+                external_addrs: Some(ExternalAddrConfig {
+                    confirmations: Some(0),
+                    ..Default::default()
+                }),
+                ..valid()
+            },
+            "the external address confirmations must be positive",
+        );
+        assert_invalid(
+            P2pConfig {
+                request_topics: vec![KEY_HANDOVER_TOPIC.to_string()],
+                ..valid()
+            },
+            "topic is reserved",
+        );
+        assert_invalid(
+            P2pConfig {
+                pubsub: Some(PubsubConfig {
+                    mesh_n: Some(20),
+                    ..Default::default()
+                }),
+                ..valid()
+            },
+            "mesh_n_low (5) <= mesh_n (20) <= mesh_n_high (12)",
+        );
+        assert_invalid(
+            P2pConfig {
+                req_resp: Some(ReqRespConfig {
+                    peer_rate_limit: Some(RateLimit { burst: 0, per_second: 1 }),
+                    ..Default::default()
+                }),
+                ..valid()
+            },
+            "protocol `default`: rate limits must be positive",
+        );
+        assert_invalid(
+            P2pConfig {
+                req_resp_protocols: HashMap::from([(BROADCAST_REPLY_PROTOCOL.to_string(), ReqRespConfig::default())]),
+                ..valid()
+            },
+            "protocol is reserved",
+        );
+        assert_invalid(
+            P2pConfig {
+                stream: Some(StreamConfig {
+                    dir: "/var/lib/tinyp2p/streams".into(),
+                    partial_ttl: Some(0),
+                    ..Default::default()
+                }),
+                ..valid()
+            },
+            "the stream limits must be positive",
+        );
+        assert_invalid(
+            P2pConfig {
+                control: Some(ControlEndpoint::Tcp("0.0.0.0:9000".parse().unwrap())),
+                ..valid()
+            },
+            "the control API must be bound to a loopback address",
+        );
+        assert_invalid(
+            P2pConfig {
+                control: Some(ControlEndpoint::Tcp("127.0.0.1:9000".parse().unwrap())),
+                ..valid()
+            },
+            "the TCP control API requires a token file",
+        );
+        assert_invalid(
+            P2pConfig {
+                metrics_addr: Some("0.0.0.0:9090".parse().unwrap()),
+                ..valid()
+            },
+            "the metrics must be served on a loopback address",
+        );
+    }
+
+    #[test]
+    fn validate_rejects_an_invalid_secret() {
+        let config = P2pConfig {
+            secret: Some("0OIl".to_string()),
+            ..valid()
+        };
+        assert!(matches!(config.validate(), Err(P2pError::InvalidSecretKey(KeyFormat::Base58(_), _))));
+    }
+
+    #[test]
+    fn dump_omits_the_secret() {
+        let secret = crate::new_secret_key();
+        let config = P2pConfig {
+            secret: Some(secret.clone()),
+            ..valid()
+        };
+        assert!(!config.to_toml().unwrap().contains(&secret));
+        assert!(!config.to_json().unwrap().contains(&secret));
+    }
+}
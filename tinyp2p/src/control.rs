@@ -0,0 +1,424 @@
+//! JSON-RPC 2.0 control API of a running node, served on a Unix socket or a loopback TCP port.
+//!
+//! Every request and response is a single line of JSON. The methods mirror the `Client`:
+//!
+//! - `status`: the `NodeStatus` of the node.
+//! - `known_peers`: the peer IDs of the known peers.
+//! - `bandwidth`: the `BandwidthReport` of the node.
+//! - `set_boot_node`: `{"boot_node": "<multiaddr>/p2p/<peer id>"}`.
+//! - `send_request`: `{"peer": "<peer id>", "data": <payload>, "protocol": "<name>"}`, returns the response payload.
+//! - `broadcast`: `{"topic": "<topic>", "data": <payload>}`.
+//! - `subscribe`: `{"topic": "<topic>", "requests": <bool>}`, its messages, or its broadcast requests if
+//!   `requests` is true, are passed to the `EventHandler` of the node.
+//!
+//! Payloads are UTF-8 strings or arrays of bytes. Responses are returned as strings if they are valid UTF-8.
+//!
+//! The Unix socket is only accessible by the owner of the node. On TCP, every request must carry the token of
+//! the node's token file in a `"token"` member, the connection is closed otherwise.
+
+use std::{io, path::Path};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{config::ControlEndpoint, error::P2pError, protocol::DEFAULT_PROTOCOL, service::Client};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The code of the errors returned by the node.
+const NODE_ERROR: i64 = -32000;
+/// The code of the requests without a valid token.
+const UNAUTHORIZED: i64 = -32001;
+
+/// The maximum size of a request line, which holds payloads as JSON arrays of bytes.
+const MAX_LINE_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// The token of the node, required on TCP.
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcResponse {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<P2pError> for RpcError {
+    fn from(err: P2pError) -> Self {
+        RpcError::new(NODE_ERROR, err)
+    }
+}
+
+/// A payload, as a UTF-8 string or an array of bytes.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Payload> for Vec<u8> {
+    fn from(payload: Payload) -> Self {
+        match payload {
+            Payload::Text(text) => text.into_bytes(),
+            Payload::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Payload::Text(text),
+            Err(err) => Payload::Bytes(err.into_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SetBootNodeParams {
+    boot_node: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendRequestParams {
+    peer: String,
+    data: Payload,
+    #[serde(default)]
+    protocol: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BroadcastParams {
+    topic: String,
+    data: Payload,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeParams {
+    topic: String,
+    /// Subscribe to the broadcast requests on the topic instead of its messages.
+    #[serde(default)]
+    requests: bool,
+}
+
+/// The control API of a node, served once the `Server` runs.
+pub(crate) struct ControlServer {
+    pub endpoint: ControlEndpoint,
+    /// The token authenticating the requests, required on TCP.
+    pub token: Option<String>,
+    pub client: Client,
+}
+
+/// Read the token of the control API from `path`, which must only be accessible by its owner.
+pub fn read_token(path: &Path) -> Result<String, P2pError> {
+    let invalid = |reason: String| P2pError::InvalidConfig(format!("control token file {}: {}", path.display(), reason));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)
+            .map_err(|err| invalid(err.to_string()))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            return Err(invalid(format!("mode {:o} is accessible by other users, expected 600", mode & 0o777)));
+        }
+    }
+    let token = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    match token.trim() {
+        "" => Err(invalid("empty token".to_string())),
+        token => Ok(token.to_string()),
+    }
+}
+
+/// Serve the control API, driving the node with its `Client`.
+pub(crate) async fn serve(control: ControlServer) {
+    if let Err(err) = listen(&control).await {
+        error!("❌ Failed to serve the control API on {}: {}", control.endpoint, err);
+    }
+}
+
+async fn listen(control: &ControlServer) -> io::Result<()> {
+    let endpoint = &control.endpoint;
+    match endpoint {
+        #[cfg(unix)]
+        ControlEndpoint::Unix(path) => {
+            let listener = bind_private(path)?;
+            info!("📣 Serving the control API on {}", endpoint);
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle_connection(stream, control.client.clone(), None));
+            }
+        }
+        #[cfg(not(unix))]
+        ControlEndpoint::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+        ControlEndpoint::Tcp(addr) => {
+            // Checked by `P2pConfig::validate`, but the API must never be served without it.
+            let Some(token) = control.token.clone() else {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "No control token configured"));
+            };
+            let listener = TcpListener::bind(addr).await?;
+            info!("📣 Serving the control API on {}", endpoint);
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle_connection(stream, control.client.clone(), Some(token.clone())));
+            }
+        }
+    }
+}
+
+/// Bind the socket in a private directory, and move it to `path` once only its owner can access it,
+/// so that it is never reachable with the permissions of the umask.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    // Remove the socket left behind by a previous run, but nothing else.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = parent.join(format!(".{}.{}.tmp", name, std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join("socket");
+    let result = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&dir);
+    result
+}
+
+/// Compare the tokens in constant time.
+fn is_valid_token(expected: &str, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Read a line of at most `MAX_LINE_SIZE` bytes, returns `None` at the end of the stream.
+async fn read_line<R>(reader: &mut R) -> io::Result<Option<String>>
+    where
+        R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.len() > MAX_LINE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Request exceeds {} bytes", MAX_LINE_SIZE),
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+async fn handle_connection<S>(stream: S, client: Client, token: Option<String>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    loop {
+        let line = match read_line(&mut reader).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("❗ Control connection failed: {}", err);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut authorized = true;
+        let response = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) if token.as_deref().is_some_and(|token| !is_valid_token(token, request.token.as_deref())) => {
+                warn!("❗ Rejected control request without a valid token");
+                authorized = false;
+                new_response(request.id, Err(RpcError::new(UNAUTHORIZED, "Invalid token")))
+            }
+            Ok(request) => {
+                let client = client.clone();
+                let id = request.id.clone();
+                // The `Client` blocks until the `Server` responds.
+                let result = tokio::task::spawn_blocking(move || dispatch(&client, request))
+                    .await
+                    .unwrap_or_else(|err| Err(RpcError::new(NODE_ERROR, err)));
+                new_response(id, result)
+            }
+            Err(err) => new_response(Value::Null, Err(RpcError::new(PARSE_ERROR, err))),
+        };
+
+        let mut response = serde_json::to_vec(&response).expect("responses are serializable");
+        response.push(b'\n');
+        if writer.write_all(&response).await.is_err() || !authorized {
+            return;
+        }
+    }
+}
+
+fn new_response(id: Value, result: Result<Value, RpcError>) -> RpcResponse {
+    let (result, error) = match result {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    RpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result,
+        error,
+    }
+}
+
+fn dispatch(client: &Client, request: RpcRequest) -> Result<Value, RpcError> {
+    match request.method.as_str() {
+        "status" => to_value(client.get_node_status()),
+        "known_peers" => to_value(client.get_known_peers()),
+        "bandwidth" => to_value(client.get_bandwidth()),
+        "set_boot_node" => {
+            let params: SetBootNodeParams = params(request.params)?;
+            let boot_node = params.boot_node.parse()?;
+            match client.set_boot_node(boot_node) {
+                Ok(success) => Ok(json!(success.to_string())),
+                Err(err) => Err(P2pError::from(err).into()),
+            }
+        }
+        "send_request" => {
+            let params: SendRequestParams = params(request.params)?;
+            let protocol = params.protocol.as_deref().unwrap_or(DEFAULT_PROTOCOL);
+            let response = client.request_on(protocol, &params.peer, params.data.into())?;
+            to_value(Payload::from(response))
+        }
+        "broadcast" => {
+            let params: BroadcastParams = params(request.params)?;
+            client.broadcast(params.topic, params.data.into());
+            Ok(Value::Null)
+        }
+        "subscribe" => {
+            let params: SubscribeParams = params(request.params)?;
+            if params.requests {
+                client.subscribe_requests(&params.topic)?;
+            } else {
+                client.subscribe(params.topic)?;
+            }
+            Ok(Value::Null)
+        }
+        method => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+    }
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| RpcError::new(NODE_ERROR, err))
+}
+
+/// Call a method of the control API of the node served on `endpoint`, returns its result.
+/// The `token` is required on TCP, see `read_token`.
+pub async fn call(
+    endpoint: &ControlEndpoint,
+    token: Option<&str>,
+    method: &str,
+    params: Value,
+) -> Result<Value, P2pError> {
+    let mut request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    if let Some(token) = token {
+        request["token"] = json!(token);
+    }
+    let mut request = serde_json::to_vec(&request).expect("requests are serializable");
+    request.push(b'\n');
+
+    let line = match endpoint {
+        #[cfg(unix)]
+        ControlEndpoint::Unix(path) => exchange(tokio::net::UnixStream::connect(path).await?, &request).await?,
+        #[cfg(not(unix))]
+        ControlEndpoint::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )
+            .into())
+        }
+        ControlEndpoint::Tcp(addr) => exchange(TcpStream::connect(addr).await?, &request).await?,
+    };
+
+    let response: RpcResponse =
+        serde_json::from_str(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(P2pError::ControlError(error.code, error.message)),
+        (result, None) => Ok(result.unwrap_or(Value::Null)),
+    }
+}
+
+async fn exchange<S>(stream: S, request: &[u8]) -> io::Result<String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    writer.write_all(request).await?;
+    read_line(&mut BufReader::new(reader))
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"))
+}
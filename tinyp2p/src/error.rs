@@ -1,12 +1,20 @@
-use std::io;
+use std::{collections::HashMap, io, time::Duration};
 
-use libp2p::{gossipsub, multiaddr, swarm, TransportError};
+use libp2p::{gossipsub, identity, multiaddr, swarm, PeerId, TransportError};
 use tokio::sync::oneshot;
 
+use crate::{keys::KeyFormat, protocol::ResponseError};
+
 #[derive(thiserror::Error, Debug)]
 pub enum P2pError {
-    #[error("Invalid secret key: {0}")]
-    InvalidSecretKey(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid {0} secret key: {1}")]
+    InvalidSecretKey(KeyFormat, String),
+    #[error("Keystore error: {0}")]
+    KeystoreError(String),
+    #[error(transparent)]
+    SigningError(#[from] identity::SigningError),
     #[error("Invalid address")]
     InvalidAddress(#[from] multiaddr::Error),
     #[error("Invalid peer ID")]
@@ -17,6 +25,20 @@ pub enum P2pError {
     ListenError(#[from] TransportError<io::Error>),
     #[error("The remote peer rejected the request")]
     RequestRejected,
+    #[error("The remote peer is rate limiting our requests")]
+    RequestThrottled,
+    #[error("The request was cancelled")]
+    RequestCancelled,
+    #[error("Quorum not reached: {} of {required} responses", responses.len())]
+    QuorumNotReached {
+        /// The successful responses received before the quorum became unreachable.
+        responses: HashMap<PeerId, Vec<u8>>,
+        required: usize,
+    },
+    #[error("Request timeout exceeds limit: {0:?} > {1:?}")]
+    RequestTimeoutTooLong(Duration, Duration),
+    #[error("Unsupported request-response protocol: {0}")]
+    UnsupportedProtocol(String),
     #[error(transparent)]
     ChanError(#[from] oneshot::error::RecvError),
     #[error("Failed to build pub/sub behaviour: {0}")]
@@ -25,6 +47,22 @@ pub enum P2pError {
     SubscribeError(#[from] gossipsub::SubscriptionError),
     #[error(transparent)]
     PublishError(#[from] gossipsub::PublishError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Stream failed: {0}")]
+    StreamError(String),
+    #[error("Control API error {0}: {1}")]
+    ControlError(i64, String),
+}
+
+impl From<ResponseError> for P2pError {
+    fn from(err: ResponseError) -> Self {
+        match err {
+            ResponseError::Rejected => P2pError::RequestRejected,
+            ResponseError::Throttled => P2pError::RequestThrottled,
+            ResponseError::UnsupportedProtocol => P2pError::UnsupportedProtocol(String::new()),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -0,0 +1,180 @@
+//! The external addresses of the node, the addresses peers can reach it at.
+//!
+//! They come from the configured public addresses, from the listen addresses on public interfaces,
+//! and from the addresses observed by peers, which are only confirmed once enough distinct peers report them.
+//! An observed address expires if no peer reports it again for a while, e.g. once the node changed networks.
+
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use tokio::time::Instant;
+
+use crate::config::ExternalAddrConfig;
+
+/// The number of distinct peers which must observe an address before it is confirmed.
+const DEFAULT_CONFIRMATIONS: usize = 2;
+/// The maximum number of candidate addresses, the least observed is dropped beyond it.
+const MAX_CANDIDATES: usize = 32;
+/// How long a confirmed observed address stays advertised without being observed again.
+/// Peers observe the node on every identify exchange, every 5 minutes by default.
+const DEFAULT_OBSERVED_TTL: Duration = Duration::from_secs(30 * 60);
+
+pub(crate) struct ExternalAddrs {
+    confirmations: usize,
+    observed_ttl: Duration,
+    advertise_listen_addrs: bool,
+    public_addrs: Vec<Multiaddr>,
+    /// The observed addresses which are not confirmed yet, with the peers which reported them.
+    candidates: HashMap<Multiaddr, HashSet<PeerId>>,
+    /// The confirmed observed addresses, with the last time they were observed.
+    confirmed: HashMap<Multiaddr, Instant>,
+}
+
+impl ExternalAddrs {
+    pub fn new(config: Option<&ExternalAddrConfig>) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        Self {
+            confirmations: config.confirmations.unwrap_or(DEFAULT_CONFIRMATIONS),
+            observed_ttl: config
+                .observed_ttl
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_OBSERVED_TTL),
+            advertise_listen_addrs: config.advertise_listen_addrs.unwrap_or(true),
+            public_addrs: config.public_addrs,
+            candidates: HashMap::new(),
+            confirmed: HashMap::new(),
+        }
+    }
+
+    /// The configured public addresses, which are confirmed from the start.
+    pub fn public_addrs(&self) -> &[Multiaddr] {
+        &self.public_addrs
+    }
+
+    /// Whether the listen address is on a public interface, and so is an external address.
+    pub fn is_external_listen_addr(&self, addr: &Multiaddr) -> bool {
+        self.advertise_listen_addrs && is_public(addr)
+    }
+
+    /// Record that `peer` observed the local node at `observed`, translated to the ports of the listen addresses.
+    /// Returns the addresses which are newly confirmed by this observation.
+    pub fn observe(&mut self, peer: PeerId, observed: &Multiaddr, listen_addrs: &[Multiaddr]) -> Vec<Multiaddr> {
+        if !is_routable(observed) {
+            return Vec::new();
+        }
+
+        let translated: HashSet<_> = listen_addrs
+            .iter()
+            .filter_map(|listen_addr| translate(listen_addr, observed))
+            .collect();
+        let now = Instant::now();
+        let mut confirmed = Vec::new();
+        for addr in translated {
+            if let Some(last_observed) = self.confirmed.get_mut(&addr) {
+                *last_observed = now;
+                continue;
+            }
+            let observers = self.candidates.entry(addr.clone()).or_default();
+            observers.insert(peer);
+            if observers.len() >= self.confirmations {
+                self.candidates.remove(&addr);
+                self.confirmed.insert(addr.clone(), now);
+                confirmed.push(addr);
+            }
+        }
+
+        while self.candidates.len() > MAX_CANDIDATES {
+            let least_observed = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, observers)| observers.len())
+                .map(|(addr, _)| addr.clone());
+            if let Some(addr) = least_observed {
+                self.candidates.remove(&addr);
+            }
+        }
+        confirmed
+    }
+
+    /// The observed addresses which are not confirmed yet.
+    pub fn candidates(&self) -> Vec<Multiaddr> {
+        self.candidates.keys().cloned().collect()
+    }
+
+    /// When the next confirmed observed address expires, if any.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.confirmed.values().min().map(|last_observed| *last_observed + self.observed_ttl)
+    }
+
+    /// Remove the confirmed observed addresses which weren't observed for the TTL, returns them.
+    pub fn expire(&mut self) -> Vec<Multiaddr> {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .confirmed
+            .iter()
+            .filter(|(_, last_observed)| **last_observed + self.observed_ttl <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in &expired {
+            self.confirmed.remove(addr);
+        }
+        expired
+    }
+}
+
+/// Combine the IP address observed by a peer with the transport and port of a listen address,
+/// as the port of outbound connections is ephemeral.
+fn translate(listen_addr: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+    let mut listen = listen_addr.iter();
+    let mut observed = observed.iter();
+    let ip = match (listen.next()?, observed.next()?) {
+        (Protocol::Ip4(_), ip @ Protocol::Ip4(_)) | (Protocol::Ip6(_), ip @ Protocol::Ip6(_)) => ip,
+        _ => return None,
+    };
+    let rest: Vec<_> = listen.collect();
+    // The transports must match, e.g. a TCP observation says nothing about a QUIC listener.
+    if mem::discriminant(rest.first()?) != mem::discriminant(&observed.next()?) {
+        return None;
+    }
+    Some([ip].into_iter().chain(rest).collect())
+}
+
+/// Whether peers outside of the host may reach the address.
+fn is_routable(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => !ip.is_unspecified() && !ip.is_loopback(),
+        Some(Protocol::Ip6(ip)) => !ip.is_unspecified() && !ip.is_loopback(),
+        _ => false,
+    }
+}
+
+/// Whether the address is reachable from the internet.
+fn is_public(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => is_public_ipv4(&ip),
+        Some(Protocol::Ip6(ip)) => is_public_ipv6(&ip),
+        _ => false,
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0b1100_0000) == 64;
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || shared)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+    let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+    !(ip.is_unspecified() || ip.is_loopback() || unique_local || link_local)
+}
@@ -0,0 +1,289 @@
+//! Encrypted key files, so that node secrets aren't stored in plain config.
+//!
+//! The keypair is encrypted with ChaCha20-Poly1305, under a key derived from a passphrase with scrypt.
+//! The file is JSON, its binary fields are base58 encoded, and it must only be accessible by its owner.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use libp2p::identity::Keypair;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::P2pError;
+
+/// The environment variable holding the passphrase of the keystore, if not configured.
+pub const PASSPHRASE_ENV: &str = "TINYP2P_KEYSTORE_PASSPHRASE";
+
+const VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The scrypt cost parameters of new key files, the cost is lowered in tests.
+#[cfg(not(test))]
+const SCRYPT_LOG_N: u8 = 15;
+#[cfg(test)]
+const SCRYPT_LOG_N: u8 = 10;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    kdf: Kdf,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+enum Kdf {
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+/// Encrypt the keypair with the passphrase and save it to `path`, readable and writable by its owner only.
+/// Fails if `path` exists, so that an existing identity is never overwritten.
+pub fn save_keypair(path: impl AsRef<Path>, keypair: &Keypair, passphrase: &str) -> Result<(), P2pError> {
+    let path = path.as_ref();
+    write_new(path, &encrypt(keypair, passphrase)?).map_err(|err| match err.kind() {
+        io::ErrorKind::AlreadyExists => {
+            P2pError::KeystoreError(format!("Key file {} already exists", path.display()))
+        }
+        _ => err.into(),
+    })
+}
+
+fn encrypt(keypair: &Keypair, passphrase: &str) -> Result<Vec<u8>, P2pError> {
+    let plaintext = keypair
+        .to_protobuf_encoding()
+        .map_err(|err| P2pError::KeystoreError(err.to_string()))?;
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let kdf = Kdf::Scrypt {
+        log_n: SCRYPT_LOG_N,
+        r: SCRYPT_R,
+        p: SCRYPT_P,
+    };
+    let cipher = ChaCha20Poly1305::new(&derive_key(&kdf, passphrase, &salt)?);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| P2pError::KeystoreError("Failed to encrypt the keypair".to_string()))?;
+
+    let key_file = KeyFile {
+        version: VERSION,
+        kdf,
+        salt: bs58::encode(salt).into_string(),
+        nonce: bs58::encode(nonce).into_string(),
+        ciphertext: bs58::encode(ciphertext).into_string(),
+    };
+    Ok(serde_json::to_vec_pretty(&key_file).expect("key files are serializable"))
+}
+
+/// Write `content` to a temporary file next to `path`, and link it to `path` once it is on disk,
+/// so that `path` is either missing or complete. Fails with `AlreadyExists` if `path` exists.
+fn write_new(path: &Path, content: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = dir.join(format!(".{}.{}.tmp", name, rand::random::<u64>()));
+
+    let result = owner_only_options().open(&temp_path).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()?;
+        // Unlike a rename, a link never replaces an existing file.
+        fs::hard_link(&temp_path, path)?;
+        sync_dir(dir)
+    });
+    let _ = fs::remove_file(&temp_path);
+    result
+}
+
+/// Load the keypair saved at `path`, decrypting it with the passphrase.
+/// Fails if the file is accessible by other users than its owner.
+pub fn load_keypair(path: impl AsRef<Path>, passphrase: &str) -> Result<Keypair, P2pError> {
+    let path = path.as_ref();
+    check_permissions(path)?;
+
+    let key_file: KeyFile = serde_json::from_slice(&fs::read(path)?)
+        .map_err(|err| P2pError::KeystoreError(format!("Invalid key file {}: {}", path.display(), err)))?;
+    if key_file.version != VERSION {
+        return Err(P2pError::KeystoreError(format!(
+            "Unsupported key file version {}",
+            key_file.version
+        )));
+    }
+
+    let salt = decode_field("salt", &key_file.salt)?;
+    let nonce = decode_field("nonce", &key_file.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(P2pError::KeystoreError("Invalid nonce length".to_string()));
+    }
+    let ciphertext = decode_field("ciphertext", &key_file.ciphertext)?;
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(&key_file.kdf, passphrase, &salt)?);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| P2pError::KeystoreError("Wrong passphrase or corrupted key file".to_string()))?;
+
+    Keypair::from_protobuf_encoding(&plaintext).map_err(|err| P2pError::KeystoreError(err.to_string()))
+}
+
+/// Load the keypair saved at `path`, or generate one with `generate` and save it if the file doesn't exist.
+/// If another process creates the file meanwhile, its keypair is loaded instead.
+pub fn load_or_create_keypair(
+    path: impl AsRef<Path>,
+    passphrase: &str,
+    generate: impl FnOnce() -> Result<Keypair, P2pError>,
+) -> Result<Keypair, P2pError> {
+    let path = path.as_ref();
+    if path.exists() {
+        return load_keypair(path, passphrase);
+    }
+
+    let keypair = generate()?;
+    match write_new(path, &encrypt(&keypair, passphrase)?) {
+        Ok(()) => Ok(keypair),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => load_keypair(path, passphrase),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn derive_key(kdf: &Kdf, passphrase: &str, salt: &[u8]) -> Result<Key, P2pError> {
+    match kdf {
+        Kdf::Scrypt { log_n, r, p } => {
+            let params = scrypt::Params::new(*log_n, *r, *p, 32)
+                .map_err(|err| P2pError::KeystoreError(format!("Invalid scrypt parameters: {}", err)))?;
+            let mut key = Key::default();
+            scrypt::scrypt(passphrase.as_bytes(), salt, &params, key.as_mut_slice())
+                .map_err(|err| P2pError::KeystoreError(err.to_string()))?;
+            Ok(key)
+        }
+    }
+}
+
+fn decode_field(name: &str, value: &str) -> Result<Vec<u8>, P2pError> {
+    bs58::decode(value)
+        .into_vec()
+        .map_err(|err| P2pError::KeystoreError(format!("Invalid {}: {}", name, err)))
+}
+
+#[cfg(unix)]
+fn owner_only_options() -> fs::OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true).mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+fn owner_only_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    options
+}
+
+/// Persist the new entries of the directory.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), P2pError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(P2pError::KeystoreError(format!(
+            "Key file {} is accessible by other users (mode {:o}), restrict it to 600",
+            path.display(),
+            mode & 0o777
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), P2pError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// A fresh directory for the key files of a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tinyp2p-keystore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = test_dir("round-trip").join("node.key");
+        let keypair = Keypair::generate_ed25519();
+        save_keypair(&path, &keypair, PASSPHRASE).unwrap();
+
+        let loaded = load_keypair(&path, PASSPHRASE).unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let path = test_dir("wrong-passphrase").join("node.key");
+        save_keypair(&path, &Keypair::generate_ed25519(), PASSPHRASE).unwrap();
+
+        assert!(matches!(load_keypair(&path, "wrong"), Err(P2pError::KeystoreError(_))));
+    }
+
+    #[test]
+    fn existing_file_is_not_overwritten() {
+        let path = test_dir("existing").join("node.key");
+        let keypair = Keypair::generate_ed25519();
+        save_keypair(&path, &keypair, PASSPHRASE).unwrap();
+
+        assert!(save_keypair(&path, &Keypair::generate_ed25519(), PASSPHRASE).is_err());
+        let loaded = load_or_create_keypair(&path, PASSPHRASE, || Ok(Keypair::generate_ed25519())).unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions_are_checked() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = test_dir("permissions").join("node.key");
+        save_keypair(&path, &Keypair::generate_ed25519(), PASSPHRASE).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(load_keypair(&path, PASSPHRASE), Err(P2pError::KeystoreError(_))));
+    }
+}
@@ -6,18 +6,33 @@
  * @FilePath: /cg_p2p_client (copy)/tinyp2p/src/lib.rs
  * @Description: 这是默认设置,请设置`customMade`, 打开koroFileHeader查看配置 进行设置: https://github.com/OBKoro1/koro1FileHeader/wiki/%E9%85%8D%E7%BD%AE
  */
+mod bandwidth;
 pub mod config;
+pub mod control;
 pub mod error;
+mod external_addr;
+mod keys;
+pub mod keystore;
 
+mod metrics;
 pub mod protocol;
+mod rate_limit;
+mod response_cache;
 mod service;
 mod transport;
 
+pub use bandwidth::{BandwidthReport, Usage};
 pub use config::*;
 pub use error::P2pError;
-pub use service::{new, new_secret_key, Client, EventHandler, Server};
+pub use keys::{generate as generate_keypair, KeyFormat};
+pub use service::{
+    new, new_secret_key, new_secret_key_of, BroadcastReplies, Client, ConnectionDirection, ConnectionInfo, EventHandler,
+    NodeStatus, PeerInfo, Quorum, RequestContext, RequestHandle, RequestHandler, RequestOptions,
+    Server,
+};
 
 // Re-export libp2p types.
+pub use libp2p::gossipsub::MessageAcceptance;
 pub use libp2p::request_response::ProtocolSupport;
-pub use libp2p::swarm::DialError;
-pub use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+pub use libp2p::swarm::{DialError, ListenError};
+pub use libp2p::{core::transport::ListenerId, multiaddr::Protocol, Multiaddr, PeerId};
@@ -0,0 +1,345 @@
+//! `tinyp2p` command-line node, to debug the network without writing Rust.
+
+use std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::{Args, Parser, Subcommand};
+use libp2p::gossipsub::PublishError;
+use log::{info, warn};
+use tinyp2p::{
+    control, keystore, protocol::DEFAULT_PROTOCOL, ConnectionInfo, ControlEndpoint, EventHandler, KeyType, KeystoreConfig, P2pConfig, P2pError, PeerId,
+    PeerIdWithMultiaddr, PeerInfo, RequestOptions,
+};
+use tokio::runtime::Runtime;
+
+/// The interval between two checks of the connections of a starting node.
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Parser, Debug)]
+#[command(name = "tinyp2p", version, about = "A tinyp2p node for debugging the network")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a new secret key, and print it with its peer ID.
+    Keygen {
+        /// One of `ed25519`, `secp256k1` or `ecdsa`.
+        #[arg(long, default_value = "ed25519")]
+        key_type: KeyType,
+        /// Save the key to this encrypted key file instead of printing the secret.
+        /// Its passphrase is read from `TINYP2P_KEYSTORE_PASSPHRASE`.
+        #[arg(long)]
+        keystore: Option<PathBuf>,
+    },
+    /// Print the peer ID of a secret key.
+    PeerId {
+        /// The base58 secret, read from the config, `TINYP2P_SECRET` or `TINYP2P_KEYSTORE` if not provided.
+        secret: Option<String>,
+        /// The type of the secret.
+        #[arg(long)]
+        key_type: Option<KeyType>,
+        /// A PEM, PKCS#8, SEC1 or protobuf key file instead of the secret.
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Start a node and log its events until interrupted.
+    Run(NodeArgs),
+    /// Send a request to a peer and print the response.
+    Request {
+        #[command(flatten)]
+        node: NodeArgs,
+        /// The peer ID of the target.
+        peer: String,
+        /// The request payload.
+        data: String,
+        /// The named request-response protocol to send the request on.
+        #[arg(long, default_value = DEFAULT_PROTOCOL)]
+        protocol: String,
+        /// Check that the payload is JSON, and pretty-print a JSON response.
+        #[arg(long)]
+        json: bool,
+        /// Timeout of each attempt in seconds.
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+        /// Number of retries if the peer can't be reached.
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+    },
+    /// Publish a message on a topic.
+    Publish {
+        #[command(flatten)]
+        node: NodeArgs,
+        topic: String,
+        message: String,
+    },
+    /// Subscribe to topics and print their messages until interrupted.
+    Subscribe {
+        #[command(flatten)]
+        node: NodeArgs,
+        #[arg(required = true)]
+        topics: Vec<String>,
+    },
+    /// Join the network and print the status of the node,
+    /// or print the status of a running node with `--control`.
+    Status {
+        #[command(flatten)]
+        node: NodeArgs,
+        /// The control endpoint of a running node, `unix:<path>` or `<ip>:<port>`.
+        #[arg(long)]
+        control: Option<ControlEndpoint>,
+        /// The token file of the control endpoint, required on TCP.
+        #[arg(long)]
+        control_token_file: Option<PathBuf>,
+    },
+}
+
+/// The options of the commands which start a node.
+#[derive(Args, Debug)]
+struct NodeArgs {
+    /// A TOML or JSON config file, overridden by `TINYP2P_*` environment variables and the options below.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// An address to listen on, repeat it to listen on several, e.g. over TCP and QUIC.
+    #[arg(long = "addr")]
+    addrs: Vec<String>,
+    /// The base58 secret of the node, a random one if not provided.
+    #[arg(long)]
+    secret: Option<String>,
+    /// The boot node, as `<multiaddr>/p2p/<peer id>`.
+    #[arg(long)]
+    boot_node: Option<PeerIdWithMultiaddr>,
+    /// Maximum seconds to wait for the node to connect to a peer before running the command.
+    #[arg(long, default_value_t = 5)]
+    wait: u64,
+    /// Answer inbound requests with their payload, they are rejected otherwise.
+    #[arg(long)]
+    echo: bool,
+}
+
+impl NodeArgs {
+    fn config(&self, topics: &[String]) -> Result<P2pConfig, P2pError> {
+        let mut config = match &self.config {
+            Some(path) => P2pConfig::from_file(path)?,
+            None => P2pConfig::default(),
+        }
+        .apply_env()?;
+        if !self.addrs.is_empty() {
+            config.listen_addrs = self.addrs.clone();
+        }
+        if config.listen_addrs.is_empty() {
+            config.listen_addrs = vec!["/ip4/0.0.0.0/tcp/0".to_string()];
+        }
+        if let Some(secret) = &self.secret {
+            config.secret = Some(secret.clone());
+        }
+        if let Some(boot_node) = &self.boot_node {
+            config.boot_node = Some(boot_node.clone());
+        }
+        for topic in topics {
+            if !config.pubsub_topics.contains(topic) {
+                config.pubsub_topics.push(topic.clone());
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Logs the events of the node, and prints the inbound broadcasts.
+#[derive(Debug)]
+struct CliHandler {
+    /// Whether to answer inbound requests with their payload.
+    echo: bool,
+}
+
+impl EventHandler for CliHandler {
+    fn handle_peer_connected(&self, peer_id: &PeerId, connection: &ConnectionInfo) {
+        info!("📣 Connected to {} at {}", peer_id, connection.remote_address);
+    }
+
+    fn handle_peer_disconnected(&self, peer_id: &PeerId, _connection: &ConnectionInfo, cause: Option<String>) {
+        info!("📣 Disconnected from {}: {}", peer_id, cause.unwrap_or_default());
+    }
+
+    fn handle_identify(&self, peer_id: &PeerId, info: &PeerInfo) {
+        info!("📣 Identified {} as {} ({})", peer_id, info.agent_version, info.protocol_version);
+    }
+
+    fn handle_inbound_request(&self, request: Vec<u8>) -> Result<Vec<u8>, P2pError> {
+        info!("📣 <<<< Inbound request: {}", String::from_utf8_lossy(&request));
+        if self.echo {
+            Ok(request)
+        } else {
+            Err(P2pError::RequestRejected)
+        }
+    }
+
+    fn handle_inbound_broadcast(&self, topic: String, message: Vec<u8>) {
+        println!("{}: {}", topic, String::from_utf8_lossy(&message));
+    }
+}
+
+/// Start a node in the background, and wait for it to connect to a peer, for at most `args.wait`.
+fn start_node(runtime: &Runtime, args: &NodeArgs, topics: &[String]) -> Result<tinyp2p::Client, P2pError> {
+    let config = args.config(topics)?;
+    let (client, mut server) = tinyp2p::new::<CliHandler>(config)?;
+    server.set_event_handler(CliHandler { echo: args.echo });
+    runtime.spawn(async move { server.run().await });
+
+    let deadline = Instant::now() + Duration::from_secs(args.wait);
+    while client.get_node_status().connections.is_empty() {
+        if Instant::now() >= deadline {
+            warn!("❗ Not connected to any peer after {}s", args.wait);
+            break;
+        }
+        thread::sleep(STATUS_POLL_INTERVAL);
+    }
+    Ok(client)
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    if let Err(err) = run(Cli::parse()) {
+        eprintln!("❌ {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), P2pError> {
+    let runtime = Runtime::new()?;
+    // The node binds its listeners when it is created, which requires the runtime.
+    let _guard = runtime.enter();
+
+    match cli.command {
+        Command::Keygen {
+            key_type,
+            keystore: None,
+        } => {
+            let secret = tinyp2p::new_secret_key_of(key_type);
+            let config = P2pConfig {
+                key_type,
+                secret: Some(secret.clone()),
+                ..Default::default()
+            };
+            println!("secret: {}", secret);
+            println!("peer id: {}", config.gen_keypair()?.public().to_peer_id());
+        }
+        Command::Keygen {
+            key_type,
+            keystore: Some(path),
+        } => {
+            let config = KeystoreConfig {
+                path,
+                passphrase: None,
+            };
+            let keypair = tinyp2p::generate_keypair(key_type);
+            keystore::save_keypair(&config.path, &keypair, &config.passphrase()?)?;
+            println!("key file: {}", config.path.display());
+            println!("peer id: {}", keypair.public().to_peer_id());
+        }
+        Command::PeerId {
+            secret,
+            key_type,
+            key_file,
+            config,
+        } => {
+            let mut config = match config {
+                Some(path) => P2pConfig::from_file(path)?.apply_env()?,
+                None => P2pConfig::default().apply_env()?,
+            };
+            if secret.is_some() || key_file.is_some() {
+                // The arguments replace the key sources of the config.
+                config.secret = secret;
+                config.key_file = key_file;
+                config.keystore = None;
+            }
+            if let Some(key_type) = key_type {
+                config.key_type = key_type;
+            }
+            println!("{}", config.load_keypair()?.public().to_peer_id());
+        }
+        Command::Run(node) => {
+            let config = node.config(&[])?;
+            let (_client, mut server) = tinyp2p::new::<CliHandler>(config)?;
+            server.set_event_handler(CliHandler { echo: node.echo });
+            runtime.block_on(server.run());
+        }
+        Command::Request {
+            node,
+            peer,
+            data,
+            protocol,
+            json,
+            timeout,
+            retries,
+        } => {
+            if json {
+                serde_json::from_str::<serde_json::Value>(&data)
+                    .map_err(|err| P2pError::InvalidConfig(format!("the request is not JSON: {}", err)))?;
+            }
+            let client = start_node(&runtime, &node, &[])?;
+            let options = RequestOptions {
+                timeout: Some(Duration::from_secs(timeout)),
+                retries,
+                backoff: Duration::from_secs(1),
+                ..Default::default()
+            };
+            let response = client
+                .send_request(&protocol, &peer, data.into_bytes(), options)?
+                .wait()?;
+            match serde_json::from_slice::<serde_json::Value>(&response) {
+                Ok(value) if json => println!("{:#}", value),
+                _ => println!("{}", String::from_utf8_lossy(&response)),
+            }
+        }
+        Command::Publish { node, topic, message } => {
+            let client = start_node(&runtime, &node, std::slice::from_ref(&topic))?;
+            // The subscriptions of the peers arrive shortly after connecting to them.
+            let deadline = Instant::now() + Duration::from_secs(node.wait);
+            loop {
+                match client.publish(topic.clone(), message.clone().into_bytes()) {
+                    Err(P2pError::PublishError(PublishError::InsufficientPeers)) if Instant::now() < deadline => {
+                        thread::sleep(STATUS_POLL_INTERVAL)
+                    }
+                    result => break result?,
+                }
+            }
+            // Give the message time to leave before the node stops.
+            thread::sleep(Duration::from_secs(1));
+        }
+        Command::Subscribe { node, topics } => {
+            let _client = start_node(&runtime, &node, &topics)?;
+            info!("📣 Subscribed to {}", topics.join(", "));
+            loop {
+                thread::park();
+            }
+        }
+        Command::Status {
+            control: Some(endpoint),
+            control_token_file,
+            ..
+        } => {
+            let token = control_token_file.as_deref().map(control::read_token).transpose()?;
+            let token = token.as_deref();
+            let status = runtime.block_on(control::call(&endpoint, token, "status", serde_json::Value::Null))?;
+            let bandwidth = runtime.block_on(control::call(&endpoint, token, "bandwidth", serde_json::Value::Null))?;
+            println!("{:#}", status);
+            println!("{:#}", bandwidth);
+        }
+        Command::Status { node, control: None, .. } => {
+            let client = start_node(&runtime, &node, &[])?;
+            println!("{:#?}", client.get_node_status());
+            println!("{:#?}", client.get_bandwidth());
+        }
+    }
+    Ok(())
+}
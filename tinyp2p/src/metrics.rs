@@ -0,0 +1,250 @@
+//! Prometheus metrics of the node: the libp2p metrics, and our own counters of requests and broadcasts.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use libp2p::{
+    metrics::{Metrics as Libp2pMetrics, Recorder},
+    request_response::OutboundFailure,
+};
+use log::{error, info};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet, EncodeLabelValue},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::{sleep, timeout},
+};
+
+/// The time a scraper has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// The delay before accepting connections again after a failure, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProtocolLabels {
+    protocol: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FailureLabels {
+    protocol: String,
+    kind: FailureKind,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum FailureKind {
+    DialFailure,
+    Timeout,
+    ConnectionClosed,
+    UnsupportedProtocols,
+}
+
+impl From<&OutboundFailure> for FailureKind {
+    fn from(failure: &OutboundFailure) -> Self {
+        match failure {
+            OutboundFailure::DialFailure => FailureKind::DialFailure,
+            OutboundFailure::Timeout => FailureKind::Timeout,
+            OutboundFailure::ConnectionClosed => FailureKind::ConnectionClosed,
+            OutboundFailure::UnsupportedProtocols => FailureKind::UnsupportedProtocols,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BroadcastLabels {
+    topic: String,
+    direction: Direction,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// The metrics of the node.
+pub struct Metrics {
+    registry: Arc<Registry>,
+    libp2p: Libp2pMetrics,
+    inbound_requests: Family<ProtocolLabels, Counter>,
+    outbound_requests: Family<ProtocolLabels, Counter>,
+    outbound_failures: Family<FailureLabels, Counter>,
+    broadcasts: Family<BroadcastLabels, Counter>,
+    handler_latency: Family<ProtocolLabels, Histogram, fn() -> Histogram>,
+    pending_requests: Gauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::default();
+        let libp2p = Libp2pMetrics::new(&mut registry);
+
+        let inbound_requests = Family::default();
+        let outbound_requests = Family::default();
+        let outbound_failures = Family::default();
+        let broadcasts = Family::default();
+        let handler_latency: Family<ProtocolLabels, Histogram, fn() -> Histogram> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.001, 2.0, 12)));
+        let pending_requests = Gauge::default();
+        {
+            let registry = registry.sub_registry_with_prefix("tinyp2p");
+            registry.register(
+                "inbound_requests",
+                "Number of inbound requests",
+                inbound_requests.clone(),
+            );
+            registry.register(
+                "outbound_requests",
+                "Number of outbound request attempts",
+                outbound_requests.clone(),
+            );
+            registry.register(
+                "outbound_failures",
+                "Number of failed outbound request attempts",
+                outbound_failures.clone(),
+            );
+            registry.register(
+                "broadcasts",
+                "Number of accepted inbound and published outbound broadcasts",
+                broadcasts.clone(),
+            );
+            registry.register(
+                "handler_latency_seconds",
+                "Time spent handling inbound requests",
+                handler_latency.clone(),
+            );
+            registry.register(
+                "pending_requests",
+                "Number of outbound requests awaiting a response",
+                pending_requests.clone(),
+            );
+        }
+
+        Self {
+            registry: Arc::new(registry),
+            libp2p,
+            inbound_requests,
+            outbound_requests,
+            outbound_failures,
+            broadcasts,
+            handler_latency,
+            pending_requests,
+        }
+    }
+
+    pub fn registry(&self) -> Arc<Registry> {
+        self.registry.clone()
+    }
+
+    pub fn record_inbound_request(&self, protocol: &str) {
+        self.inbound_requests
+            .get_or_create(&ProtocolLabels {
+                protocol: protocol.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn record_outbound_request(&self, protocol: &str) {
+        self.outbound_requests
+            .get_or_create(&ProtocolLabels {
+                protocol: protocol.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn record_outbound_failure(&self, protocol: &str, failure: &OutboundFailure) {
+        self.outbound_failures
+            .get_or_create(&FailureLabels {
+                protocol: protocol.to_string(),
+                kind: failure.into(),
+            })
+            .inc();
+    }
+
+    pub fn record_broadcast(&self, topic: &str, direction: Direction) {
+        self.broadcasts
+            .get_or_create(&BroadcastLabels {
+                topic: topic.to_string(),
+                direction,
+            })
+            .inc();
+    }
+
+    pub fn record_handler_latency(&self, protocol: &str, latency: Duration) {
+        self.handler_latency
+            .get_or_create(&ProtocolLabels {
+                protocol: protocol.to_string(),
+            })
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn set_pending_requests(&self, count: usize) {
+        self.pending_requests.set(count as i64);
+    }
+}
+
+impl<T> Recorder<T> for Metrics
+    where
+        Libp2pMetrics: Recorder<T>,
+{
+    fn record(&self, event: &T) {
+        self.libp2p.record(event)
+    }
+}
+
+/// Serve the metrics in the Prometheus text format on `addr`.
+/// The requests aren't parsed, every path serves the metrics.
+pub async fn serve(addr: SocketAddr, registry: Arc<Registry>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("❌ Failed to serve metrics on {}: {}", addr, err);
+            return;
+        }
+    };
+    info!("📣 Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("❌ Failed to accept metrics connection: {}", err);
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            if !matches!(timeout(READ_TIMEOUT, stream.read(&mut request)).await, Ok(Ok(_))) {
+                return;
+            }
+
+            let mut body = String::new();
+            if let Err(err) = encode(&mut body, &registry) {
+                error!("❌ Failed to encode metrics: {}", err);
+                return;
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
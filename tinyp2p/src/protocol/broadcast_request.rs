@@ -0,0 +1,84 @@
+//! Request-reply over pub/sub.
+//!
+//! A broadcast request is published on the companion topic `<topic>/request`, in an envelope
+//! carrying a correlation ID and the peer to reply to. Nodes opt in to the requests of a topic by
+//! subscribing to its companion topic, and reply directly to that peer, with a request on the
+//! reserved `broadcast-reply` protocol.
+
+use std::io;
+
+use libp2p::PeerId;
+
+/// The request-response protocol of the replies to broadcast requests.
+pub const BROADCAST_REPLY_PROTOCOL: &str = "broadcast-reply";
+
+const REQUEST_TOPIC_SUFFIX: &str = "/request";
+
+/// Returns the companion topic of broadcast requests on `topic`.
+pub fn request_topic(topic: &str) -> String {
+    format!("{}{}", topic, REQUEST_TOPIC_SUFFIX)
+}
+
+/// Returns the topic of a companion topic of broadcast requests.
+pub fn base_topic(topic: &str) -> Option<&str> {
+    topic.strip_suffix(REQUEST_TOPIC_SUFFIX)
+}
+
+/// The envelope of a broadcast request.
+/// It is encoded as a varint ID, a length-prefixed peer ID, and the payload.
+#[derive(Debug, Clone)]
+pub struct BroadcastRequest {
+    /// The correlation ID of the replies.
+    pub id: u64,
+    /// The peer to reply to.
+    pub reply_to: PeerId,
+    pub payload: Vec<u8>,
+}
+
+impl BroadcastRequest {
+    pub fn encode(&self) -> Vec<u8> {
+        let reply_to = self.reply_to.to_bytes();
+        let mut id = unsigned_varint::encode::u64_buffer();
+        let mut length = unsigned_varint::encode::usize_buffer();
+        [
+            unsigned_varint::encode::u64(self.id, &mut id),
+            unsigned_varint::encode::usize(reply_to.len(), &mut length),
+            reply_to.as_slice(),
+            self.payload.as_slice(),
+        ]
+        .concat()
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let (id, data) = unsigned_varint::decode::u64(data).map_err(invalid_data)?;
+        let (length, data) = unsigned_varint::decode::usize(data).map_err(invalid_data)?;
+        if length > data.len() {
+            return Err(invalid_data("Truncated peer ID"));
+        }
+        let (reply_to, payload) = data.split_at(length);
+        Ok(Self {
+            id,
+            reply_to: PeerId::from_bytes(reply_to).map_err(invalid_data)?,
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+/// Encode a reply to the broadcast request `id`, as a varint ID followed by the payload.
+pub fn encode_reply(id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = unsigned_varint::encode::u64_buffer();
+    [unsigned_varint::encode::u64(id, &mut buffer), payload].concat()
+}
+
+/// Decode a reply, returns the ID of the broadcast request and the payload.
+pub fn decode_reply(data: &[u8]) -> io::Result<(u64, Vec<u8>)> {
+    let (id, payload) = unsigned_varint::decode::u64(data).map_err(invalid_data)?;
+    Ok((id, payload.to_vec()))
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,115 @@
+//! Payload compression of the request-response protocol.
+//!
+//! A compressed frame is a flag byte naming the algorithm, followed by the payload.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::config::Compression;
+
+const FLAG_NONE: u8 = 0;
+const FLAG_DEFLATE: u8 = 1;
+const FLAG_ZSTD: u8 = 2;
+
+/// Encode `payload` into a frame, compressed with `compression` if it is at least `threshold` bytes.
+/// The payload is sent uncompressed if compressing it doesn't make it smaller.
+pub fn encode(payload: &[u8], compression: Option<Compression>, threshold: usize) -> io::Result<Vec<u8>> {
+    let compressed = match compression {
+        Some(compression) if payload.len() >= threshold => Some(compress(compression, payload)?),
+        _ => None,
+    };
+
+    let frame = match compressed {
+        Some((flag, data)) if data.len() < payload.len() => [&[flag], data.as_slice()].concat(),
+        _ => [&[FLAG_NONE], payload].concat(),
+    };
+    Ok(frame)
+}
+
+/// Decode a frame, failing if the decompressed payload exceeds `max_size`.
+pub fn decode(frame: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let (flag, data) = frame.split_first().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing compression flag")
+    })?;
+
+    let payload = match *flag {
+        FLAG_NONE => data.to_vec(),
+        FLAG_DEFLATE => read_bounded(DeflateDecoder::new(data), max_size)?,
+        FLAG_ZSTD => read_bounded(zstd::stream::read::Decoder::new(data)?, max_size)?,
+        flag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown compression flag: {}", flag),
+            ))
+        }
+    };
+
+    if payload.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Decompressed size exceeds limit: > {}", max_size),
+        ));
+    }
+    Ok(payload)
+}
+
+fn compress(compression: Compression, payload: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    match compression {
+        Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(payload)?;
+            Ok((FLAG_DEFLATE, encoder.finish()?))
+        }
+        Compression::Zstd => Ok((FLAG_ZSTD, zstd::stream::encode_all(payload, 0)?)),
+    }
+}
+
+// Stop decompressing one byte past the limit, so that a decompression bomb is never fully expanded.
+fn read_bounded<R: Read>(reader: R, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 1024;
+
+    #[test]
+    fn round_trip() {
+        let payload = vec![7u8; 4 * MAX_SIZE];
+        for compression in [None, Some(Compression::Deflate), Some(Compression::Zstd)] {
+            let frame = encode(&payload, compression, 0).unwrap();
+            assert_eq!(decode(&frame, payload.len()).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let frame = encode(b"payload", Some(Compression::Zstd), MAX_SIZE).unwrap();
+        assert_eq!(frame, [&[FLAG_NONE], b"payload".as_slice()].concat());
+    }
+
+    #[test]
+    fn decode_rejects_bombs() {
+        let payload = vec![0u8; MAX_SIZE + 1];
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let frame = encode(&payload, Some(compression), 0).unwrap();
+            assert!(frame.len() < MAX_SIZE);
+            assert!(decode(&frame, MAX_SIZE).is_err());
+            assert_eq!(decode(&frame, MAX_SIZE + 1).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid_frames() {
+        assert!(decode(&[], MAX_SIZE).is_err());
+        assert!(decode(&[42, 0], MAX_SIZE).is_err());
+        assert!(decode(&[FLAG_DEFLATE, 0xff, 0xff], MAX_SIZE).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Identity key rotation.
+//!
+//! A node rotating its key publishes a handover record on the reserved topic `key-handover`,
+//! signed by both its old and its new key, so that peers can move their state from the old `PeerId` to the new one.
+//! A compromised old key can sign a handover too, so peers record the first handover of each old key,
+//! and report the conflicting ones.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::P2pError;
+
+/// The topic of key handover records, every node is subscribed to it.
+pub const KEY_HANDOVER_TOPIC: &str = "key-handover";
+
+/// The domain of the signatures, so they can't be replayed in another context.
+const SIGNATURE_DOMAIN: &[u8] = b"tinyp2p-key-handover:";
+
+/// The maximum age in seconds of a handover, older ones are replays.
+const MAX_AGE: u64 = 10 * 60;
+
+/// The maximum clock skew in seconds of a handover from the future.
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// The number of handovers kept, the oldest recorded ones are forgotten first.
+const MAX_HANDOVERS: usize = 4096;
+
+/// Appends a handover to the file, the file I/O blocks so it runs off the event loop.
+pub(crate) type RecordJob = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+/// A record moving the identity of a node from its old key to its new key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyHandover {
+    pub old_key: PublicKey,
+    pub new_key: PublicKey,
+    /// The UNIX time in seconds of the rotation.
+    pub timestamp: u64,
+    /// The signature by the old key.
+    pub old_signature: Vec<u8>,
+    /// The signature by the new key.
+    pub new_signature: Vec<u8>,
+}
+
+/// The encoding of a `KeyHandover`, JSON with base58 fields.
+#[derive(Serialize, Deserialize)]
+struct EncodedKeyHandover {
+    old_key: String,
+    new_key: String,
+    timestamp: u64,
+    old_signature: String,
+    new_signature: String,
+}
+
+impl KeyHandover {
+    /// Create a record of the handover from `old` to `new`, signed by both.
+    pub fn new(old: &Keypair, new: &Keypair) -> Result<Self, P2pError> {
+        let timestamp = unix_time();
+        let message = signed_message(&old.public(), &new.public(), timestamp);
+        Ok(Self {
+            old_key: old.public(),
+            new_key: new.public(),
+            timestamp,
+            old_signature: old.sign(&message)?,
+            new_signature: new.sign(&message)?,
+        })
+    }
+
+    pub fn old_peer_id(&self) -> PeerId {
+        self.old_key.to_peer_id()
+    }
+
+    pub fn new_peer_id(&self) -> PeerId {
+        self.new_key.to_peer_id()
+    }
+
+    /// Whether both signatures are valid, and the keys differ.
+    pub fn verify(&self) -> bool {
+        let message = signed_message(&self.old_key, &self.new_key, self.timestamp);
+        self.old_key != self.new_key
+            && self.old_key.verify(&message, &self.old_signature)
+            && self.new_key.verify(&message, &self.new_signature)
+    }
+
+    /// Whether the handover was signed recently, within the clock skew of the peers.
+    pub fn is_fresh(&self) -> bool {
+        self.is_fresh_at(unix_time())
+    }
+
+    fn is_fresh_at(&self, now: u64) -> bool {
+        self.timestamp <= now.saturating_add(MAX_CLOCK_SKEW) && now.saturating_sub(MAX_AGE) <= self.timestamp
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let encoded = EncodedKeyHandover {
+            old_key: bs58::encode(self.old_key.encode_protobuf()).into_string(),
+            new_key: bs58::encode(self.new_key.encode_protobuf()).into_string(),
+            timestamp: self.timestamp,
+            old_signature: bs58::encode(&self.old_signature).into_string(),
+            new_signature: bs58::encode(&self.new_signature).into_string(),
+        };
+        serde_json::to_vec(&encoded).expect("key handovers are serializable")
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let encoded: EncodedKeyHandover = serde_json::from_slice(data)?;
+        let key = |value: &str| {
+            PublicKey::try_decode_protobuf(&decode_base58(value)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        };
+        Ok(Self {
+            old_key: key(&encoded.old_key)?,
+            new_key: key(&encoded.new_key)?,
+            timestamp: encoded.timestamp,
+            old_signature: decode_base58(&encoded.old_signature)?,
+            new_signature: decode_base58(&encoded.new_signature)?,
+        })
+    }
+}
+
+/// The handovers processed by the node, the first one of each old `PeerId`.
+/// They are appended to a file if configured, so that conflicts are detected across restarts.
+/// Only the last `MAX_HANDOVERS` recorded are kept.
+#[derive(Debug, Default)]
+pub(crate) struct KeyHandovers {
+    handovers: HashMap<PeerId, KeyHandover>,
+    order: VecDeque<PeerId>,
+    file: Option<PathBuf>,
+}
+
+impl KeyHandovers {
+    /// Load the handovers recorded in the file, one JSON record per line.
+    pub(crate) fn load(file: Option<PathBuf>) -> io::Result<Self> {
+        let mut handovers = Self::default();
+        if let Some(path) = &file {
+            let data = match fs::read(path) {
+                Ok(data) => data,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err),
+            };
+            for line in data.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()) {
+                let handover = KeyHandover::decode(line)?;
+                if handovers.get(&handover.old_peer_id()).is_none() {
+                    handovers.remember(handover);
+                }
+            }
+        }
+        handovers.file = file;
+        Ok(handovers)
+    }
+
+    /// The first handover of the old `PeerId`.
+    pub(crate) fn get(&self, old: &PeerId) -> Option<&KeyHandover> {
+        self.handovers.get(old)
+    }
+
+    /// Record the first handover of its old `PeerId`, returns the job appending it to the file if configured.
+    pub(crate) fn insert(&mut self, handover: KeyHandover) -> Option<RecordJob> {
+        let mut line = handover.encode();
+        line.push(b'\n');
+        self.remember(handover);
+        let path = self.file.clone()?;
+        Some(Box::new(move || {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(&line)?;
+            file.sync_data()
+        }))
+    }
+
+    fn remember(&mut self, handover: KeyHandover) {
+        if self.order.len() >= MAX_HANDOVERS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.handovers.remove(&oldest);
+            }
+        }
+        self.order.push_back(handover.old_peer_id());
+        self.handovers.insert(handover.old_peer_id(), handover);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn signed_message(old_key: &PublicKey, new_key: &PublicKey, timestamp: u64) -> Vec<u8> {
+    let mut message = SIGNATURE_DOMAIN.to_vec();
+    for key in [old_key.encode_protobuf(), new_key.encode_protobuf()] {
+        message.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        message.extend_from_slice(&key);
+    }
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message
+}
+
+fn decode_base58(value: &str) -> io::Result<Vec<u8>> {
+    bs58::decode(value)
+        .into_vec()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let handover = KeyHandover::new(&Keypair::generate_ed25519(), &Keypair::generate_ed25519()).unwrap();
+        assert!(handover.verify());
+        let decoded = KeyHandover::decode(&handover.encode()).unwrap();
+        assert_eq!(decoded, handover);
+        assert!(decoded.verify());
+    }
+
+    #[test]
+    fn tampered_handover_is_invalid() {
+        let handover = KeyHandover::new(&Keypair::generate_ed25519(), &Keypair::generate_ed25519()).unwrap();
+
+        let mut tampered = handover.clone();
+        tampered.timestamp += 1;
+        assert!(!tampered.verify());
+
+        let mut tampered = handover.clone();
+        tampered.new_key = Keypair::generate_ed25519().public();
+        assert!(!tampered.verify());
+
+        let mut tampered = handover;
+        tampered.old_signature[0] ^= 1;
+        assert!(!tampered.verify());
+    }
+
+    #[test]
+    fn wrong_signer_is_invalid() {
+        let (old, new, other) = (
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+            Keypair::generate_ed25519(),
+        );
+        let mut handover = KeyHandover::new(&old, &new).unwrap();
+        let message = signed_message(&old.public(), &new.public(), handover.timestamp);
+        handover.old_signature = other.sign(&message).unwrap();
+        assert!(!handover.verify());
+
+        let same = Keypair::generate_ed25519();
+        assert!(!KeyHandover::new(&same, &same).unwrap().verify());
+    }
+
+    #[test]
+    fn stale_handover_is_not_fresh() {
+        let handover = KeyHandover::new(&Keypair::generate_ed25519(), &Keypair::generate_ed25519()).unwrap();
+        let now = handover.timestamp;
+        assert!(handover.is_fresh_at(now));
+        assert!(handover.is_fresh_at(now + MAX_AGE));
+        assert!(!handover.is_fresh_at(now + MAX_AGE + 1));
+        assert!(handover.is_fresh_at(now - MAX_CLOCK_SKEW));
+        assert!(!handover.is_fresh_at(now - MAX_CLOCK_SKEW - 1));
+    }
+
+    #[test]
+    fn handovers_are_persisted() {
+        let dir = std::env::temp_dir().join(format!("tinyp2p-handovers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("handovers");
+        let old = Keypair::generate_ed25519();
+        let first = KeyHandover::new(&old, &Keypair::generate_ed25519()).unwrap();
+
+        let mut handovers = KeyHandovers::load(Some(path.clone())).unwrap();
+        let record = handovers.insert(first.clone()).unwrap();
+        record().unwrap();
+        let loaded = KeyHandovers::load(Some(path)).unwrap();
+        assert_eq!(loaded.get(&old.public().to_peer_id()), Some(&first));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn handovers_are_bounded() {
+        let mut handovers = KeyHandovers::default();
+        let old = (0..MAX_HANDOVERS + 1).map(|_| Keypair::generate_ed25519()).collect::<Vec<_>>();
+        let new = Keypair::generate_ed25519();
+        for key in &old {
+            assert!(handovers.insert(KeyHandover::new(key, &new).unwrap()).is_none());
+        }
+        assert_eq!(handovers.handovers.len(), MAX_HANDOVERS);
+        assert!(handovers.get(&old[0].public().to_peer_id()).is_none());
+        assert!(handovers.get(&old[MAX_HANDOVERS].public().to_peer_id()).is_some());
+    }
+}
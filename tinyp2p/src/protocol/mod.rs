@@ -1,17 +1,12 @@
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    io,
-    net::IpAddr,
-    time::Duration,
-};
+use std::{collections::HashMap, io, net::IpAddr, time::Duration};
 
 use either::Either;
+
 use libp2p::{
-    gossipsub::{self, IdentTopic},
+    gossipsub::{self, IdentTopic, MessageAcceptance, MessageId},
     identify,
-    identity::Keypair,
-    kad::{store::MemoryStore, Kademlia},
+    identity::{Keypair, PublicKey},
+    kad::{store::MemoryStore, Kademlia, Mode},
     multiaddr::Protocol,
     ping,
     request_response::{self, RequestId, ResponseChannel},
@@ -21,15 +16,35 @@ use libp2p::{
 use log::debug;
 use void::Void;
 
-use crate::{config::ReqRespConfig, error::P2pError};
+use crate::{
+    config::{IdentifyConfig, PubsubConfig, ReqRespConfig},
+    error::P2pError,
+};
 
+/// The identify protocol version of the nodes, unless configured otherwise.
+pub const DEFAULT_IDENTIFY_PROTOCOL: &str = "/cyber-guardians/identify/1.0.0";
+
+mod broadcast_request;
+mod compression;
+mod key_handover;
 mod req_resp;
+mod stream;
 
+pub use broadcast_request::BROADCAST_REPLY_PROTOCOL;
+pub(crate) use broadcast_request::{base_topic, decode_reply, encode_reply, request_topic, BroadcastRequest};
+pub use key_handover::{KeyHandover, KEY_HANDOVER_TOPIC};
+pub(crate) use key_handover::KeyHandovers;
 use req_resp::GenericCodec;
-pub use req_resp::ResponseType;
+pub use req_resp::{
+    ProtocolVersion, Request, ResponseError, ResponseType, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_PROTOCOL,
+    DEFAULT_REQUEST_TIMEOUT,
+};
+use stream::StreamCodec;
+pub(crate) use stream::{digest, IncomingStreams, StreamOutcome};
+pub use stream::{ReceivedStream, StreamRequest, StreamResponse, MAX_CHUNK_SIZE};
 
 /// BehaviourErr combines all possible errors generated by the subbehaviours.
-pub type BehaviourErr = Either<Either<Either<Either<io::Error, io::Error>, Void>, Void>, Void>;
+pub type BehaviourErr = Either<Either<Either<Either<Either<io::Error, io::Error>, Void>, Void>, Void>, Void>;
 
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
@@ -43,6 +58,9 @@ pub struct Behaviour {
     // `req_resp` is used for sending requests and responses.
     req_resp: request_response::Behaviour<GenericCodec>,
 
+    // `stream` is used for sending large payloads in chunks.
+    stream: request_response::Behaviour<StreamCodec>,
+
     // `pubsub` is used for broadcasting messages.
     pubsub: gossipsub::Behaviour,
 }
@@ -50,21 +68,26 @@ pub struct Behaviour {
 impl Behaviour {
     pub fn new(
         local_key: Keypair,
+        identify_config: Option<IdentifyConfig>,
         pubsub_topics: Vec<String>,
+        pubsub_config: Option<PubsubConfig>,
         req_resp_config: Option<ReqRespConfig>,
+        req_resp_protocols: &HashMap<String, ReqRespConfig>,
     ) -> Result<Self, P2pError> {
         let local_pubkey = local_key.public();
         let local_id = local_pubkey.to_peer_id();
 
+        let mut kad = Kademlia::new(local_id, MemoryStore::new(local_id));
+        // Answer DHT queries before any external address is confirmed, peers on the local network can reach the node.
+        kad.set_mode(Some(Mode::Server));
+
         Ok(Self {
-            kad: Kademlia::new(local_id, MemoryStore::new(local_id)),
-            identify: identify::Behaviour::new(identify::Config::new(
-                "/cyber-guardians/identify/1.0.0".to_string(),
-                local_pubkey,
-            )),
+            kad,
+            identify: Self::new_identify(local_pubkey, identify_config.unwrap_or_default()),
             ping: ping::Behaviour::default(),
-            req_resp: Self::new_req_resp(req_resp_config),
-            pubsub: Self::new_gossipsub(local_key, pubsub_topics)?,
+            req_resp: Self::new_req_resp(req_resp_config, req_resp_protocols),
+            stream: stream::new_behaviour(),
+            pubsub: Self::new_gossipsub(local_key, pubsub_topics, pubsub_config)?,
         })
     }
 
@@ -88,7 +111,23 @@ impl Behaviour {
         peers
     }
 
-    pub fn send_request(&mut self, target: &PeerId, request: Vec<u8>) -> RequestId {
+    /// Returns the pub/sub scores of all known peers, empty if peer scoring is disabled.
+    pub fn peer_scores(&self) -> HashMap<PeerId, f64> {
+        self.pubsub
+            .all_peers()
+            .filter_map(|(peer, _)| self.pubsub.peer_score(peer).map(|score| (*peer, score)))
+            .collect()
+    }
+
+    /// Returns the pub/sub mesh peers of the given topic.
+    pub fn mesh_peers(&self, topic: &str) -> Vec<PeerId> {
+        self.pubsub
+            .mesh_peers(&IdentTopic::new(topic).hash())
+            .cloned()
+            .collect()
+    }
+
+    pub fn send_request(&mut self, target: &PeerId, request: Request) -> RequestId {
         self.req_resp.send_request(target, request)
     }
 
@@ -96,6 +135,19 @@ impl Behaviour {
         let _ = self.req_resp.send_response(ch, response);
     }
 
+    pub fn send_stream_request(&mut self, target: &PeerId, request: StreamRequest) -> RequestId {
+        self.stream.send_request(target, request)
+    }
+
+    pub fn send_stream_response(&mut self, ch: ResponseChannel<StreamResponse>, response: StreamResponse) {
+        let _ = self.stream.send_response(ch, response);
+    }
+
+    /// Subscribe to the given topic, returns `false` if already subscribed.
+    pub fn subscribe(&mut self, topic: &str) -> Result<bool, P2pError> {
+        Ok(self.pubsub.subscribe(&IdentTopic::new(topic))?)
+    }
+
     pub fn broadcast(&mut self, topic: String, message: Vec<u8>) -> Result<(), P2pError> {
         let topic = gossipsub::IdentTopic::new(topic);
         self.pubsub.publish(topic, message)?;
@@ -103,6 +155,19 @@ impl Behaviour {
         Ok(())
     }
 
+    /// Report the application's verdict on a received broadcast,
+    /// only accepted messages are propagated to the rest of the network.
+    pub fn report_message_validation_result(
+        &mut self,
+        message_id: &MessageId,
+        propagation_source: &PeerId,
+        acceptance: MessageAcceptance,
+    ) {
+        let _ = self
+            .pubsub
+            .report_message_validation_result(message_id, propagation_source, acceptance);
+    }
+
     pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
         if can_add_to_dht(&addr) {
             debug!("☕ Adding address {} from {:?} to the DHT.", addr, peer_id);
@@ -115,34 +180,82 @@ impl Behaviour {
         self.kad.remove_peer(peer_id);
     }
 
-    fn new_req_resp(config: Option<ReqRespConfig>) -> request_response::Behaviour<GenericCodec> {
-        if let Some(config) = config {
-            return req_resp::BehaviourBuilder::new()
+    fn new_identify(local_pubkey: PublicKey, config: IdentifyConfig) -> identify::Behaviour {
+        let protocol_version = config
+            .protocol_version
+            .unwrap_or_else(|| DEFAULT_IDENTIFY_PROTOCOL.to_string());
+        let agent_version = config
+            .agent_version
+            .unwrap_or_else(|| format!("tinyp2p/{}", env!("CARGO_PKG_VERSION")));
+        let mut identify_config = identify::Config::new(protocol_version, local_pubkey)
+            .with_agent_version(agent_version)
+            .with_push_listen_addr_updates(config.push_updates.unwrap_or(true));
+        if let Some(interval) = config.interval {
+            identify_config = identify_config.with_interval(Duration::from_secs(interval));
+        }
+        identify::Behaviour::new(identify_config)
+    }
+
+    fn new_req_resp(
+        config: Option<ReqRespConfig>,
+        protocols: &HashMap<String, ReqRespConfig>,
+    ) -> request_response::Behaviour<GenericCodec> {
+        // The replies to broadcast requests follow the configuration of the default protocol.
+        let reply_config = config.clone().unwrap_or_default();
+        let mut builder = match config {
+            Some(config) => req_resp::BehaviourBuilder::new()
                 .with_support(config.support)
                 .with_connection_keep_alive(config.connection_keep_alive)
                 .with_request_timeout(config.request_timeout)
                 .with_max_request_size(config.max_request_size)
                 .with_max_response_size(config.max_response_size)
-                .build();
+                .with_compression(config.compression, config.compression_threshold),
+            None => req_resp::BehaviourBuilder::default(),
+        };
+
+        for (name, config) in protocols {
+            builder = builder.with_protocol(name.clone(), config);
         }
+        builder = builder.with_protocol(BROADCAST_REPLY_PROTOCOL, &reply_config);
 
-        req_resp::BehaviourBuilder::default().build()
+        builder.build()
     }
 
     fn new_gossipsub(
         local_key: Keypair,
         topics: Vec<String>,
+        config: Option<PubsubConfig>,
     ) -> Result<gossipsub::Behaviour, P2pError> {
-        let message_id_fn = |message: &gossipsub::Message| {
-            let mut s = DefaultHasher::new();
-            message.data.hash(&mut s);
-            gossipsub::MessageId::from(s.finish().to_string())
-        };
+        let config = config.unwrap_or_default();
+        let message_id = config.message_id.unwrap_or_default();
+        let message_id_fn = move |message: &gossipsub::Message| message_id.message_id(message);
 
-        let gossipsub_config = gossipsub::ConfigBuilder::default()
-            .heartbeat_interval(Duration::from_secs(10))
+        let mut builder = gossipsub::ConfigBuilder::default();
+        builder
+            .heartbeat_interval(Duration::from_secs(config.heartbeat_interval.unwrap_or(10)))
             .validation_mode(gossipsub::ValidationMode::Strict)
-            .message_id_fn(message_id_fn)
+            // Messages are only forwarded once the application has validated them.
+            .validate_messages()
+            .message_id_fn(message_id_fn);
+        if let Some(mesh_n) = config.mesh_n {
+            builder.mesh_n(mesh_n);
+        }
+        if let Some(mesh_n_low) = config.mesh_n_low {
+            builder.mesh_n_low(mesh_n_low);
+        }
+        if let Some(mesh_n_high) = config.mesh_n_high {
+            builder.mesh_n_high(mesh_n_high);
+        }
+        if let Some(history_length) = config.history_length {
+            builder.history_length(history_length);
+        }
+        if let Some(flood_publish) = config.flood_publish {
+            builder.flood_publish(flood_publish);
+        }
+        if let Some(max_transmit_size) = config.max_transmit_size {
+            builder.max_transmit_size(max_transmit_size);
+        }
+        let gossipsub_config = builder
             .build()
             .map_err(|err| P2pError::PubsubBuildError(err.to_string()))?;
 
@@ -152,6 +265,18 @@ impl Behaviour {
         )
             .map_err(|err| P2pError::PubsubBuildError(err.to_string()))?;
 
+        if let Some(peer_score) = config.peer_score {
+            let mut params = gossipsub::PeerScoreParams::default();
+            for (topic, topic_params) in peer_score.topics {
+                params
+                    .topics
+                    .insert(IdentTopic::new(topic).hash(), topic_params.into());
+            }
+            gossipsub
+                .with_peer_score(params, peer_score.thresholds.into())
+                .map_err(P2pError::PubsubBuildError)?;
+        }
+
         for t in topics {
             let topic = IdentTopic::new(t);
             gossipsub.subscribe(&topic)?;
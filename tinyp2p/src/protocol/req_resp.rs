@@ -1,12 +1,113 @@
-use std::{io, iter, time::Duration};
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use libp2p::{
     futures::prelude::*,
     request_response::{self, Behaviour, Codec, ProtocolSupport},
 };
+use serde::{Deserialize, Serialize};
 
-pub type ResponseType = Result<Vec<u8>, ()>;
+use super::compression;
+use crate::config::{Compression, ReqRespConfig};
+
+/// The name of the protocol configured by `P2pConfig::req_resp`.
+pub const DEFAULT_PROTOCOL: &str = "default";
+
+/// The default timeout for inbound and outbound requests.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default keep-alive timeout of idle connections.
+const DEFAULT_CONNECTION_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// The default maximum size of requests and responses.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The default minimum size of a payload to compress.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// The maximum size of a request header.
+const MAX_HEADER_SIZE: usize = 1024;
+
+/// The initial capacity of a payload buffer, it only grows as data is actually received.
+const INITIAL_BUFFER_SIZE: usize = 8 * 1024;
+
+pub type ResponseType = Result<Vec<u8>, ResponseError>;
+
+/// The reason why a request didn't get a successful response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseError {
+    /// The remote peer rejected the request, or the request failed.
+    Rejected,
+    /// The remote peer is rate limiting our requests.
+    Throttled,
+    /// The protocol of the request isn't supported in that direction.
+    UnsupportedProtocol,
+}
+
+impl ResponseError {
+    /// The code written on the wire, `Rejected` has none since it closes the substream instead.
+    fn code(&self) -> Option<u8> {
+        match self {
+            ResponseError::Rejected => None,
+            ResponseError::Throttled => Some(1),
+            ResponseError::UnsupportedProtocol => Some(2),
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => ResponseError::Throttled,
+            2 => ResponseError::UnsupportedProtocol,
+            _ => ResponseError::Rejected,
+        }
+    }
+}
+
+/// A request on one of the named protocols.
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// The name of the protocol.
+    pub protocol: String,
+    /// The negotiated version of an inbound request, exposed to handlers by `RequestContext::version`.
+    /// Outbound requests use the newest version the remote peer supports, whatever its value.
+    pub(crate) version: ProtocolVersion,
+    /// The key identifying a request across retries, so that the remote peer handles it at most once.
+    /// It is sent in the header, so it is dropped on `V1`.
+    pub idempotency_key: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+/// The header written before the payload of a request.
+#[derive(Debug, Serialize, Deserialize)]
+struct RequestHeader {
+    protocol: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+}
+
+/// The codec settings of a named protocol.
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    /// Maximum size of requests, once decompressed.
+    max_request_size: usize,
+    /// Maximum size of responses, once decompressed.
+    max_response_size: usize,
+    /// Compression of the payloads we write.
+    compression: Option<Compression>,
+    /// Minimum size of a payload to compress.
+    compression_threshold: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_request_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_response_size: DEFAULT_MAX_MESSAGE_SIZE,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
 
 /// The behaviour builder.
 #[derive(Debug, Clone)]
@@ -16,10 +117,8 @@ pub struct BehaviourBuilder {
     connection_keep_alive: Duration,
     /// The timeout for inbound and outbound requests.
     request_timeout: Duration,
-    /// The maximum size of requests.
-    max_request_size: usize,
-    /// The maximum size of responses.
-    max_response_size: usize,
+    /// The codec settings of the named protocols.
+    protocols: HashMap<String, Settings>,
 }
 
 impl Default for BehaviourBuilder {
@@ -33,10 +132,9 @@ impl BehaviourBuilder {
     pub fn new() -> Self {
         Self {
             support: ProtocolSupport::Full,
-            connection_keep_alive: Duration::from_secs(10),
-            request_timeout: Duration::from_secs(10),
-            max_request_size: usize::MAX,
-            max_response_size: usize::MAX,
+            connection_keep_alive: DEFAULT_CONNECTION_KEEP_ALIVE,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            protocols: HashMap::from([(DEFAULT_PROTOCOL.to_string(), Settings::default())]),
         }
     }
 
@@ -63,18 +161,66 @@ impl BehaviourBuilder {
         self
     }
 
-    /// Set the maximum size of requests.
+    /// Set the maximum size of requests of the default protocol.
     pub fn with_max_request_size(mut self, max_request_size: Option<usize>) -> Self {
         if let Some(max_request_size) = max_request_size {
-            self.max_request_size = max_request_size;
+            self.default_settings().max_request_size = max_request_size;
         }
         self
     }
 
-    /// Set the maximum size of responses.
+    /// Set the maximum size of responses of the default protocol.
     pub fn with_max_response_size(mut self, max_response_size: Option<usize>) -> Self {
         if let Some(max_response_size) = max_response_size {
-            self.max_response_size = max_response_size;
+            self.default_settings().max_response_size = max_response_size;
+        }
+        self
+    }
+
+    /// Set the compression of payloads of the default protocol.
+    pub fn with_compression(mut self, compression: Option<Compression>, threshold: Option<usize>) -> Self {
+        let settings = self.default_settings();
+        settings.compression = compression;
+        if let Some(threshold) = threshold {
+            settings.compression_threshold = threshold;
+        }
+        self
+    }
+
+    /// Add a named protocol.
+    ///
+    /// All the protocols share a single libp2p behaviour, so its support, keep-alive and
+    /// request timeout are widened to cover every protocol. The `Server` enforces the support
+    /// of each protocol in both directions, and the timeout of its outbound requests.
+    /// The keep-alive of idle connections and the timeout of inbound requests are shared.
+    pub fn with_protocol(mut self, name: impl Into<String>, config: &ReqRespConfig) -> Self {
+        let defaults = Settings::default();
+        self.protocols.insert(
+            name.into(),
+            Settings {
+                max_request_size: config.max_request_size.unwrap_or(defaults.max_request_size),
+                max_response_size: config.max_response_size.unwrap_or(defaults.max_response_size),
+                compression: config.compression,
+                compression_threshold: config
+                    .compression_threshold
+                    .unwrap_or(defaults.compression_threshold),
+            },
+        );
+
+        let support = config.support.clone().unwrap_or(ProtocolSupport::Full);
+        self.support = match (
+            self.support.inbound() || support.inbound(),
+            self.support.outbound() || support.outbound(),
+        ) {
+            (true, false) => ProtocolSupport::Inbound,
+            (false, true) => ProtocolSupport::Outbound,
+            _ => ProtocolSupport::Full,
+        };
+        if let Some(secs) = config.connection_keep_alive {
+            self.connection_keep_alive = self.connection_keep_alive.max(Duration::from_secs(secs));
+        }
+        if let Some(secs) = config.request_timeout {
+            self.request_timeout = self.request_timeout.max(Duration::from_secs(secs));
         }
         self
     }
@@ -82,11 +228,15 @@ impl BehaviourBuilder {
     /// Build a `Behaviour` with the given configuration.
     pub fn build(self) -> Behaviour<GenericCodec> {
         let codec = GenericCodec {
-            max_request_size: self.max_request_size,
-            max_response_size: self.max_response_size,
+            settings: Arc::new(self.protocols),
+            protocol: None,
         };
 
-        let protocols = iter::once((GenericProtocol, self.support));
+        // Listed from the newest, so that it is preferred if the remote peer supports it.
+        let protocols = ProtocolVersion::ALL
+            .iter()
+            .rev()
+            .map(|version| (*version, self.support.clone()));
 
         let mut cfg = request_response::Config::default();
         cfg.set_connection_keep_alive(self.connection_keep_alive);
@@ -94,67 +244,166 @@ impl BehaviourBuilder {
 
         Behaviour::with_codec(codec, protocols, cfg)
     }
+
+    fn default_settings(&mut self) -> &mut Settings {
+        self.protocols
+            .entry(DEFAULT_PROTOCOL.to_string())
+            .or_default()
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct GenericProtocol;
+/// The versions of the request-response wire format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    /// Requests are a length-prefixed payload, only the default protocol is supported.
+    V1,
+    /// Requests are preceded by a header naming their protocol.
+    V2,
+    /// Payloads are preceded by a flag byte naming their compression.
+    V3,
+}
+
+impl ProtocolVersion {
+    /// All the supported versions, from the oldest.
+    pub const ALL: [ProtocolVersion; 3] = [ProtocolVersion::V1, ProtocolVersion::V2, ProtocolVersion::V3];
 
-impl AsRef<str> for GenericProtocol {
+    /// The newest supported version.
+    pub fn latest() -> Self {
+        ProtocolVersion::ALL[ProtocolVersion::ALL.len() - 1]
+    }
+}
+
+impl AsRef<str> for ProtocolVersion {
     fn as_ref(&self) -> &str {
-        "/cyber-guardians/req-resp/1.0.0"
+        match self {
+            ProtocolVersion::V1 => "/cyber-guardians/req-resp/1.0.0",
+            ProtocolVersion::V2 => "/cyber-guardians/req-resp/2.0.0",
+            ProtocolVersion::V3 => "/cyber-guardians/req-resp/3.0.0",
+        }
     }
 }
 
 /// Generic request-response codec.
-/// The format of the request and response is a length-prefixed payload.
+/// A request is a length-prefixed JSON header naming its protocol (since `V2`), followed by a length-prefixed payload.
+/// A response is a length-prefixed payload.
+/// Since `V3`, a payload starts with a flag byte naming its compression, and the size limits
+/// apply to the decompressed payload.
 /// The length is encoded as a varint (variable-width integer).
 /// [What is a varint?](https://developers.google.com/protocol-buffers/docs/encoding#varints)
 #[derive(Debug, Clone)]
 pub struct GenericCodec {
-    /// Maximum size of requests.
-    max_request_size: usize,
-    /// Maximum size of responses.
-    max_response_size: usize,
+    /// The codec settings of the named protocols.
+    settings: Arc<HashMap<String, Settings>>,
+    /// The protocol of the request exchanged on the substream.
+    /// The codec is cloned for every substream, and the same instance reads (writes) the request
+    /// and then writes (reads) the response, so the response uses the settings of the right protocol.
+    protocol: Option<String>,
+}
+
+impl GenericCodec {
+    fn settings(&self, protocol: &str) -> io::Result<Settings> {
+        self.settings.get(protocol).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown protocol: {}", protocol),
+            )
+        })
+    }
+
+    fn current_settings(&self) -> io::Result<Settings> {
+        match &self.protocol {
+            Some(protocol) => self.settings(protocol),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No request on the substream",
+            )),
+        }
+    }
 }
 
 #[async_trait]
 impl Codec for GenericCodec {
-    type Protocol = GenericProtocol;
-    type Request = Vec<u8>;
+    type Protocol = ProtocolVersion;
+    type Request = Request;
     type Response = ResponseType;
 
     async fn read_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        version: &Self::Protocol,
         mut io: &mut T,
     ) -> io::Result<Self::Request>
         where
             T: AsyncRead + Unpin + Send,
     {
+        // Read the header.
+        let header = match version {
+            ProtocolVersion::V1 => RequestHeader {
+                protocol: DEFAULT_PROTOCOL.to_string(),
+                idempotency_key: None,
+            },
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                let length = unsigned_varint::aio::read_usize(&mut io)
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+                if length > MAX_HEADER_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Header size exceeds limit: {} > {}", length, MAX_HEADER_SIZE),
+                    ));
+                }
+
+                let buffer = read_payload(&mut io, length).await?;
+                serde_json::from_slice(&buffer)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            }
+        };
+        let Some(settings) = self.settings.get(&header.protocol).copied() else {
+            // The `Server` answers requests on unknown protocols with `UnsupportedProtocol`,
+            // their payload is skipped so that the remote peer can finish writing its request.
+            skip_payload(&mut io, version).await?;
+            self.protocol = Some(header.protocol.clone());
+            return Ok(Request {
+                protocol: header.protocol,
+                version: *version,
+                idempotency_key: header.idempotency_key,
+                payload: Vec::new(),
+            });
+        };
+
         // Read the length.
         let length = unsigned_varint::aio::read_usize(&mut io)
             .await
             .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
 
-        if length > self.max_request_size {
+        if length > max_frame_size(version, settings.max_request_size) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
                     "Request size exceeds limit: {} > {}",
-                    length, self.max_request_size
+                    length, settings.max_request_size
                 ),
             ));
         }
 
         // Read the payload.
-        let mut buffer = vec![0; length];
-        io.read_exact(&mut buffer).await?;
-        Ok(buffer)
+        let mut buffer = read_payload(&mut io, length).await?;
+        if *version >= ProtocolVersion::V3 {
+            buffer = compression::decode(&buffer, settings.max_request_size)?;
+        }
+
+        self.protocol = Some(header.protocol.clone());
+        Ok(Request {
+            protocol: header.protocol,
+            version: *version,
+            idempotency_key: header.idempotency_key,
+            payload: buffer,
+        })
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        version: &Self::Protocol,
         mut io: &mut T,
     ) -> io::Result<Self::Response>
         where
@@ -164,6 +413,10 @@ impl Codec for GenericCodec {
         // - Returning `Err` is considered as a protocol error.
         // - Returning `Ok(Err())` indicates that the response has been successfully read,
         //   and the content is an error.
+        // Since `V2` an error other than `Rejected` is written as an empty payload followed by its code,
+        // a `V1` empty payload is only an empty response, with nothing after it.
+        // Since `V3` a successful response is never empty, as it starts with the compression flag.
+        let settings = self.current_settings()?;
 
         // Read the length.
         let length = match unsigned_varint::aio::read_usize(&mut io).await {
@@ -171,86 +424,155 @@ impl Codec for GenericCodec {
             Err(unsigned_varint::io::ReadError::Io(err))
             if matches!(err.kind(), io::ErrorKind::UnexpectedEof) =>
                 {
-                    return Ok(Err(()))
+                    return Ok(Err(ResponseError::Rejected))
                 }
             Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidInput, err)),
         };
 
-        if length > self.max_request_size {
+        if length > max_frame_size(version, settings.max_response_size) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
                     "Response size exceeds limit: {} > {}",
-                    length, self.max_response_size
+                    length, settings.max_response_size
                 ),
             ));
         }
 
+        if length == 0 {
+            if *version == ProtocolVersion::V1 {
+                return Ok(Ok(Vec::new()));
+            }
+            let mut code = [0u8; 1];
+            return match io.read(&mut code).await? {
+                0 => Ok(Ok(Vec::new())),
+                _ => Ok(Err(ResponseError::from_code(code[0]))),
+            };
+        }
+
         // Read the payload.
-        let mut buffer = vec![0; length];
-        io.read_exact(&mut buffer).await?;
+        let mut buffer = read_payload(&mut io, length).await?;
+        if *version >= ProtocolVersion::V3 {
+            buffer = compression::decode(&buffer, settings.max_response_size)?;
+        }
         Ok(Ok(buffer))
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        version: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
         where
             T: AsyncWrite + Unpin + Send,
     {
+        let settings = self.settings(&req.protocol)?;
+
+        if *version == ProtocolVersion::V1 && req.protocol != DEFAULT_PROTOCOL {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Protocol {} is not supported by {}",
+                    req.protocol,
+                    version.as_ref()
+                ),
+            ));
+        }
+
         // Check the length.
-        if req.len() > self.max_request_size {
+        if req.payload.len() > settings.max_request_size {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
                     "Request size exceeds limit: {} > {}",
-                    req.len(),
-                    self.max_request_size
+                    req.payload.len(),
+                    settings.max_request_size
                 ),
             ));
         }
 
+        // Write the header.
+        if *version >= ProtocolVersion::V2 {
+            let header = serde_json::to_vec(&RequestHeader {
+                protocol: req.protocol.clone(),
+                idempotency_key: req.idempotency_key.clone(),
+            })?;
+            let mut length = unsigned_varint::encode::usize_buffer();
+            io.write_all(unsigned_varint::encode::usize(header.len(), &mut length))
+                .await?;
+            io.write_all(&header).await?;
+        }
+
+        let payload = match version {
+            ProtocolVersion::V1 | ProtocolVersion::V2 => req.payload,
+            ProtocolVersion::V3 => compression::encode(
+                &req.payload,
+                settings.compression,
+                settings.compression_threshold,
+            )?,
+        };
+
         // Write the length.
         {
             let mut length = unsigned_varint::encode::usize_buffer();
-            io.write_all(unsigned_varint::encode::usize(req.len(), &mut length))
+            io.write_all(unsigned_varint::encode::usize(payload.len(), &mut length))
                 .await?;
         }
 
         // Write the payload.
-        io.write_all(&req).await?;
+        io.write_all(&payload).await?;
 
         io.close().await?;
+        self.protocol = Some(req.protocol);
         Ok(())
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        version: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> io::Result<()>
         where
             T: AsyncWrite + Unpin + Send,
     {
-        // If `res` is an `Err`, we jump to closing the substream without writing anything on it.
+        // If `res` is an `Err(Rejected)`, we jump to closing the substream without writing anything on it.
         // The read side will get an `io::ErrorKind::UnexpectedEof` when trying to read the length.
+        // Since `V2` other errors are written as an empty payload followed by the error code,
+        // a `V1` peer would read it as an empty response, so it is rejected instead.
+        let code = res.as_ref().err().and_then(ResponseError::code);
+        if let Some(code) = code.filter(|_| *version >= ProtocolVersion::V2) {
+            let mut length = unsigned_varint::encode::usize_buffer();
+            io.write_all(unsigned_varint::encode::usize(0, &mut length))
+                .await?;
+            io.write_all(&[code]).await?;
+        }
+
         if let Ok(res) = res {
+            let settings = self.current_settings()?;
+
             // Check the length.
-            if res.len() > self.max_request_size {
+            if res.len() > settings.max_response_size {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
                     format!(
                         "Response size exceeds limit: {} > {}",
                         res.len(),
-                        self.max_request_size
+                        settings.max_response_size
                     ),
                 ));
             }
 
+            let res = match version {
+                ProtocolVersion::V1 | ProtocolVersion::V2 => res,
+                ProtocolVersion::V3 => compression::encode(
+                    &res,
+                    settings.compression,
+                    settings.compression_threshold,
+                )?,
+            };
+
             // Write the length.
             {
                 let mut length = unsigned_varint::encode::usize_buffer();
@@ -266,3 +588,183 @@ impl Codec for GenericCodec {
         Ok(())
     }
 }
+
+/// The maximum size of a payload on the wire, which has a compression flag since `V3`.
+fn max_frame_size(version: &ProtocolVersion, max_size: usize) -> usize {
+    match version {
+        ProtocolVersion::V1 | ProtocolVersion::V2 => max_size,
+        ProtocolVersion::V3 => max_size.saturating_add(1),
+    }
+}
+
+/// Skip a length-prefixed payload of up to the default maximum size.
+async fn skip_payload<T>(mut io: &mut T, version: &ProtocolVersion) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+{
+    let length = unsigned_varint::aio::read_usize(&mut io)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if length > max_frame_size(version, DEFAULT_MAX_MESSAGE_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Request size exceeds limit: {} > {}", length, DEFAULT_MAX_MESSAGE_SIZE),
+        ));
+    }
+    let skipped = futures::io::copy(io.take(length as u64), &mut futures::io::sink()).await?;
+    if skipped < length as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Truncated payload: {} < {}", skipped, length),
+        ));
+    }
+    Ok(())
+}
+
+/// Read a payload of `length` bytes.
+/// The length comes from the remote peer, so the buffer isn't allocated from it up front.
+pub(super) async fn read_payload<T>(io: &mut T, length: usize) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+{
+    let mut buffer = Vec::with_capacity(length.min(INITIAL_BUFFER_SIZE));
+    io.take(length as u64).read_to_end(&mut buffer).await?;
+    if buffer.len() < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Truncated payload: {} < {}", buffer.len(), length),
+        ));
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::*;
+
+    const MAX_SIZE: usize = 64;
+
+    fn codec() -> GenericCodec {
+        let settings = Settings {
+            max_request_size: MAX_SIZE,
+            max_response_size: MAX_SIZE,
+            ..Settings::default()
+        };
+        GenericCodec {
+            settings: Arc::new(HashMap::from([(DEFAULT_PROTOCOL.to_string(), settings)])),
+            protocol: None,
+        }
+    }
+
+    fn prefixed(data: &[u8]) -> Vec<u8> {
+        let mut length = unsigned_varint::encode::usize_buffer();
+        [unsigned_varint::encode::usize(data.len(), &mut length), data].concat()
+    }
+
+    fn header(protocol: &str) -> Vec<u8> {
+        prefixed(format!(r#"{{"protocol":"{}"}}"#, protocol).as_bytes())
+    }
+
+    fn read(version: ProtocolVersion, bytes: Vec<u8>) -> io::Result<Request> {
+        block_on(codec().read_request(&version, &mut Cursor::new(bytes)))
+    }
+
+    fn write_response(version: ProtocolVersion, response: ResponseType) -> Vec<u8> {
+        let mut codec = codec();
+        codec.protocol = Some(DEFAULT_PROTOCOL.to_string());
+        let mut io = Cursor::new(Vec::new());
+        block_on(codec.write_response(&version, &mut io, response)).unwrap();
+        io.into_inner()
+    }
+
+    #[test]
+    fn write_response_codes_errors_since_v2() {
+        for error in [ResponseError::Rejected, ResponseError::Throttled, ResponseError::UnsupportedProtocol] {
+            assert!(write_response(ProtocolVersion::V1, Err(error)).is_empty());
+        }
+        assert!(write_response(ProtocolVersion::V2, Err(ResponseError::Rejected)).is_empty());
+        assert_eq!(write_response(ProtocolVersion::V2, Err(ResponseError::Throttled)), [0, 1]);
+        assert_eq!(write_response(ProtocolVersion::V3, Err(ResponseError::UnsupportedProtocol)), [0, 2]);
+    }
+
+    fn read_response(version: ProtocolVersion, bytes: Vec<u8>) -> io::Result<ResponseType> {
+        let mut codec = codec();
+        codec.protocol = Some(DEFAULT_PROTOCOL.to_string());
+        block_on(codec.read_response(&version, &mut Cursor::new(bytes)))
+    }
+
+    #[test]
+    fn read_response_of_v1() {
+        let error = write_response(ProtocolVersion::V1, Err(ResponseError::Throttled));
+        assert_eq!(read_response(ProtocolVersion::V1, error).unwrap(), Err(ResponseError::Rejected));
+
+        // A trailing byte isn't taken for an error code.
+        let empty = write_response(ProtocolVersion::V1, Ok(Vec::new()));
+        assert_eq!(read_response(ProtocolVersion::V1, [empty, vec![1]].concat()).unwrap(), Ok(Vec::new()));
+
+        let response = write_response(ProtocolVersion::V1, Ok(b"response".to_vec()));
+        assert_eq!(read_response(ProtocolVersion::V1, response).unwrap(), Ok(b"response".to_vec()));
+    }
+
+    #[test]
+    fn read_response_codes_since_v2() {
+        let error = write_response(ProtocolVersion::V2, Err(ResponseError::Throttled));
+        assert_eq!(read_response(ProtocolVersion::V2, error).unwrap(), Err(ResponseError::Throttled));
+        let empty = write_response(ProtocolVersion::V2, Ok(Vec::new()));
+        assert_eq!(read_response(ProtocolVersion::V2, empty).unwrap(), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn read_request_within_limits() {
+        let payload = vec![1u8; MAX_SIZE];
+        let request = read(ProtocolVersion::V1, prefixed(&payload)).unwrap();
+        assert_eq!(request.protocol, DEFAULT_PROTOCOL);
+        assert_eq!(request.payload, payload);
+
+        let frame = [&[0u8], payload.as_slice()].concat();
+        let request = read(ProtocolVersion::V3, [header(DEFAULT_PROTOCOL), prefixed(&frame)].concat()).unwrap();
+        assert_eq!(request.payload, payload);
+    }
+
+    #[test]
+    fn read_request_rejects_oversized_header() {
+        let protocol = "p".repeat(MAX_HEADER_SIZE);
+        let bytes = [header(&protocol), prefixed(b"")].concat();
+        assert!(read(ProtocolVersion::V2, bytes).is_err());
+    }
+
+    #[test]
+    fn read_request_rejects_oversized_frame() {
+        let payload = vec![1u8; MAX_SIZE + 1];
+        assert!(read(ProtocolVersion::V1, prefixed(&payload)).is_err());
+        assert!(read(ProtocolVersion::V2, [header(DEFAULT_PROTOCOL), prefixed(&payload)].concat()).is_err());
+
+        // The compression flag is allowed on top of the limit, but not a larger payload.
+        let frame = [&[0u8], payload.as_slice()].concat();
+        assert!(read(ProtocolVersion::V3, [header(DEFAULT_PROTOCOL), prefixed(&frame)].concat()).is_err());
+    }
+
+    #[test]
+    fn read_request_rejects_decompression_past_limit() {
+        let frame = compression::encode(&[0u8; 64 * MAX_SIZE], Some(Compression::Deflate), 0).unwrap();
+        assert!(frame.len() <= MAX_SIZE + 1);
+        assert!(read(ProtocolVersion::V3, [header(DEFAULT_PROTOCOL), prefixed(&frame)].concat()).is_err());
+    }
+
+    #[test]
+    fn read_request_rejects_truncated_payload() {
+        let mut bytes = prefixed(&[1u8; MAX_SIZE]);
+        bytes.truncate(bytes.len() - 1);
+        assert!(read(ProtocolVersion::V1, bytes).is_err());
+    }
+
+    #[test]
+    fn read_request_skips_payload_of_unknown_protocol() {
+        let bytes = [header("unknown"), prefixed(&[1u8; 4 * MAX_SIZE])].concat();
+        let request = read(ProtocolVersion::V2, bytes).unwrap();
+        assert_eq!(request.protocol, "unknown");
+        assert!(request.payload.is_empty());
+    }
+}
@@ -0,0 +1,638 @@
+//! Chunked transfer of large payloads.
+//!
+//! A stream is opened with its size and ID, then sent as a sequence of bounded chunks.
+//! The receiver acknowledges each request with the offset to continue from, so an interrupted
+//! stream resumes where it stopped. The ID is the SHA-256 digest of the content, which the
+//! receiver computes as the chunks are written and verifies once the last one is.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use libp2p::{
+    futures::prelude::*,
+    request_response::{self, Behaviour, Codec, ProtocolSupport},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::req_resp::read_payload;
+use crate::config::StreamConfig;
+
+/// The maximum size of a chunk.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The default maximum size of an inbound stream.
+const DEFAULT_MAX_STREAM_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The maximum size of a header or a response.
+const MAX_HEADER_SIZE: usize = 1024;
+
+/// The timeout of a single chunk.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum number of inbound streams being received, from all peers.
+const MAX_STREAMS: usize = 64;
+
+/// The default maximum number of inbound streams being received from a single peer.
+const DEFAULT_MAX_STREAMS_PER_PEER: usize = 4;
+
+/// The default maximum total size of the inbound streams being received from a single peer.
+const DEFAULT_MAX_PENDING_BYTES_PER_PEER: u64 = 512 * 1024 * 1024;
+
+/// The default time after which an idle partial stream is dropped, with its partial file.
+const DEFAULT_PARTIAL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The minimum interval between two scans of the stream directory for stale partial files.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A request of the stream protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StreamRequest {
+    /// Announce a stream, the receiver answers with the offset to start from.
+    Open { id: String, name: String, size: u64 },
+    /// A chunk of the stream starting at `offset`.
+    Chunk {
+        id: String,
+        offset: u64,
+        /// Written after the header, not serialized in it.
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+}
+
+/// A response of the stream protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StreamResponse {
+    /// The offset to continue the stream from.
+    Ack { offset: u64 },
+    /// The whole stream was received and its digest matches its ID.
+    Complete,
+    /// The stream was refused or failed.
+    Error(String),
+}
+
+/// A stream received from a remote peer.
+#[derive(Clone, Debug)]
+pub struct ReceivedStream {
+    /// The peer which sent the stream.
+    pub peer_id: PeerId,
+    /// The base58 SHA-256 digest of the content.
+    pub id: String,
+    /// The name given by the sender, for information only.
+    pub name: String,
+    /// Where the content is stored.
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Returns the ID and the size of the content read from `data`.
+pub fn digest<R: Read>(data: &mut R) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(data, &mut hasher)?;
+    Ok((bs58::encode(hasher.finalize()).into_string(), size))
+}
+
+/// Create the behaviour of the stream protocol.
+pub fn new_behaviour() -> Behaviour<StreamCodec> {
+    let mut cfg = request_response::Config::default();
+    cfg.set_request_timeout(REQUEST_TIMEOUT);
+
+    Behaviour::with_codec(
+        StreamCodec,
+        iter::once((StreamProtocol, ProtocolSupport::Full)),
+        cfg,
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamProtocol;
+
+impl AsRef<str> for StreamProtocol {
+    fn as_ref(&self) -> &str {
+        "/cyber-guardians/stream/1.0.0"
+    }
+}
+
+/// Stream codec.
+/// A request is a length-prefixed JSON header, followed by a length-prefixed chunk which is empty
+/// unless the request is a `Chunk`.
+/// A response is a length-prefixed JSON.
+#[derive(Debug, Clone)]
+pub struct StreamCodec;
+
+#[async_trait]
+impl Codec for StreamCodec {
+    type Protocol = StreamProtocol;
+    type Request = StreamRequest;
+    type Response = StreamResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+        where
+            T: AsyncRead + Unpin + Send,
+    {
+        let mut request: StreamRequest = read_json(io).await?;
+        let data = read_bounded(io, MAX_CHUNK_SIZE).await?;
+        if let StreamRequest::Chunk { data: chunk, .. } = &mut request {
+            *chunk = data;
+        }
+        Ok(request)
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Response>
+        where
+            T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send,
+    {
+        let data = match &req {
+            StreamRequest::Chunk { data, .. } => data.as_slice(),
+            StreamRequest::Open { .. } => &[],
+        };
+        if data.len() > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk size exceeds limit: {} > {}", data.len(), MAX_CHUNK_SIZE),
+            ));
+        }
+
+        write_prefixed(io, &serde_json::to_vec(&req)?).await?;
+        write_prefixed(io, data).await?;
+
+        io.close().await?;
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send,
+    {
+        write_prefixed(io, &serde_json::to_vec(&res)?).await?;
+
+        io.close().await?;
+        Ok(())
+    }
+}
+
+async fn read_bounded<T>(io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+{
+    let length = unsigned_varint::aio::read_usize(&mut *io)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    if length > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Size exceeds limit: {} > {}", length, max_size),
+        ));
+    }
+
+    read_payload(io, length).await
+}
+
+async fn read_json<T, V>(io: &mut T) -> io::Result<V>
+    where
+        T: AsyncRead + Unpin + Send,
+        V: serde::de::DeserializeOwned,
+{
+    let buffer = read_bounded(io, MAX_HEADER_SIZE).await?;
+    serde_json::from_slice(&buffer).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+async fn write_prefixed<T>(io: &mut T, data: &[u8]) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+{
+    let mut length = unsigned_varint::encode::usize_buffer();
+    io.write_all(unsigned_varint::encode::usize(data.len(), &mut length))
+        .await?;
+    io.write_all(data).await
+}
+
+/// The result of an inbound stream request.
+#[derive(Debug)]
+pub struct StreamOutcome {
+    pub response: StreamResponse,
+    /// The bytes received so far and the size of the stream.
+    pub progress: Option<(u64, u64)>,
+    /// The stream, if this request completed it.
+    pub completed: Option<ReceivedStream>,
+}
+
+impl StreamOutcome {
+    fn error(reason: impl Into<String>) -> Self {
+        Self {
+            response: StreamResponse::Error(reason.into()),
+            progress: None,
+            completed: None,
+        }
+    }
+
+    fn ack(received: u64, size: u64) -> Self {
+        Self {
+            response: StreamResponse::Ack { offset: received },
+            progress: Some((received, size)),
+            completed: None,
+        }
+    }
+}
+
+/// The file I/O of an inbound stream request, which is run off the event loop.
+pub type StreamJob = Box<dyn FnOnce() -> StreamOutcome + Send>;
+
+fn error_job(reason: impl Into<String>) -> StreamJob {
+    let outcome = StreamOutcome::error(reason);
+    Box::new(move || outcome)
+}
+
+/// The partial file of an inbound stream, with the digest of the content written so far.
+#[derive(Debug)]
+struct PartFile {
+    /// The stream once complete.
+    stream: ReceivedStream,
+    path: PathBuf,
+    file: Option<File>,
+    hasher: Sha256,
+    received: u64,
+    /// Whether the stream was completed, or failed its verification.
+    done: bool,
+}
+
+impl PartFile {
+    /// Open the partial file if needed, hashing what an interrupted transfer already wrote to it.
+    fn ensure_open(&mut self) -> io::Result<()> {
+        if self.file.is_some() || self.done {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut received = file.metadata()?.len();
+        if received > self.stream.size {
+            file.set_len(0)?;
+            received = 0;
+        }
+        self.hasher = Sha256::new();
+        io::copy(&mut (&mut file).take(received), &mut self.hasher)?;
+        self.received = received;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<StreamOutcome> {
+        self.ensure_open()?;
+        let Some(file) = self.file.as_mut() else {
+            return Ok(StreamOutcome::error("Unknown stream"));
+        };
+        // A retried or out of order chunk, tell the sender where to continue from.
+        if offset != self.received {
+            return Ok(StreamOutcome::ack(self.received, self.stream.size));
+        }
+        let received = self.received + data.len() as u64;
+        if received > self.stream.size {
+            return Ok(StreamOutcome::error("Chunk exceeds stream size"));
+        }
+
+        file.write_all(data)?;
+        self.hasher.update(data);
+        self.received = received;
+        self.progress()
+    }
+
+    /// Ack the offset to continue from, or verify the stream and move it to its final path once fully received.
+    fn progress(&mut self) -> io::Result<StreamOutcome> {
+        self.ensure_open()?;
+        let size = self.stream.size;
+        if self.done {
+            return Ok(StreamOutcome::error("Unknown stream"));
+        }
+        if self.received < size {
+            return Ok(StreamOutcome::ack(self.received, size));
+        }
+
+        self.done = true;
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        let digest = bs58::encode(self.hasher.finalize_reset()).into_string();
+        if digest != self.stream.id {
+            fs::remove_file(&self.path)?;
+            return Ok(StreamOutcome::error("Stream digest mismatch"));
+        }
+        fs::rename(&self.path, &self.stream.path)?;
+        Ok(StreamOutcome {
+            response: StreamResponse::Complete,
+            progress: Some((size, size)),
+            completed: Some(self.stream.clone()),
+        })
+    }
+}
+
+/// An inbound stream which isn't complete yet.
+#[derive(Debug)]
+struct PartialStream {
+    peer_id: PeerId,
+    size: u64,
+    /// The path of the partial file, also known by `part`.
+    path: PathBuf,
+    /// Shared with the jobs writing the stream, which hold it one at a time.
+    part: Arc<Mutex<PartFile>>,
+    last_activity: Instant,
+}
+
+/// The inbound streams, written to `<dir>/<id>.<peer id>.part` and renamed to `<dir>/<id>` once verified.
+/// A stream belongs to the peer which opened it, and its partial file is kept when it is interrupted so that
+/// this peer can resume it, until it has been idle for the partial TTL.
+#[derive(Debug)]
+pub struct IncomingStreams {
+    /// The directory of the streams, inbound streams are refused if it isn't configured.
+    dir: Option<PathBuf>,
+    max_stream_size: u64,
+    max_streams_per_peer: usize,
+    max_pending_bytes_per_peer: u64,
+    partial_ttl: Duration,
+    streams: HashMap<String, PartialStream>,
+    last_cleanup: Option<Instant>,
+}
+
+impl IncomingStreams {
+    pub fn new(config: Option<StreamConfig>) -> Self {
+        let config = config.unwrap_or_default();
+        let dir = Some(config.dir).filter(|dir| !dir.as_os_str().is_empty());
+        Self {
+            dir,
+            max_stream_size: config.max_stream_size.unwrap_or(DEFAULT_MAX_STREAM_SIZE),
+            max_streams_per_peer: config.max_streams_per_peer.unwrap_or(DEFAULT_MAX_STREAMS_PER_PEER),
+            max_pending_bytes_per_peer: config
+                .max_pending_bytes_per_peer
+                .unwrap_or(DEFAULT_MAX_PENDING_BYTES_PER_PEER),
+            partial_ttl: config
+                .partial_ttl
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PARTIAL_TTL),
+            streams: HashMap::new(),
+            last_cleanup: None,
+        }
+    }
+
+    /// Handle an inbound request from `peer_id`, returns the job doing its file I/O.
+    pub fn handle(&mut self, peer_id: PeerId, request: StreamRequest) -> StreamJob {
+        match request {
+            StreamRequest::Open { id, name, size } => self.open(peer_id, id, name, size),
+            StreamRequest::Chunk { id, offset, data } => self.write_chunk(&peer_id, &id, offset, data),
+        }
+    }
+
+    /// Drop the stream once the job of a request completed it, or failed it.
+    pub fn settle(&mut self, peer_id: &PeerId, id: &str, response: &StreamResponse) {
+        let ended = matches!(response, StreamResponse::Complete | StreamResponse::Error(_));
+        if ended && self.streams.get(id).is_some_and(|stream| stream.peer_id == *peer_id) {
+            self.streams.remove(id);
+        }
+    }
+
+    fn open(&mut self, peer_id: PeerId, id: String, name: String, size: u64) -> StreamJob {
+        let Some(dir) = self.dir.clone() else {
+            return error_job("Streams are not accepted");
+        };
+        if size > self.max_stream_size {
+            return error_job(format!(
+                "Stream size exceeds limit: {} > {}",
+                size, self.max_stream_size
+            ));
+        }
+        // The ID is used as a file name, so it must be a plain base58 digest.
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return error_job("Invalid stream ID");
+        }
+
+        let now = Instant::now();
+        let cleanup = self.expire(now);
+        let ttl = self.partial_ttl;
+
+        // The sender resumes its own stream.
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.peer_id != peer_id || stream.size != size {
+                return error_job("Stream is already being received");
+            }
+            stream.last_activity = now;
+            let part = stream.part.clone();
+            return Box::new(move || {
+                lock(&part)
+                    .progress()
+                    .unwrap_or_else(|err| StreamOutcome::error(err.to_string()))
+            });
+        }
+
+        let (count, pending) = self
+            .streams
+            .values()
+            .filter(|stream| stream.peer_id == peer_id)
+            .fold((0, 0), |(count, pending), stream| (count + 1, pending + stream.size));
+        if self.streams.len() >= MAX_STREAMS || count >= self.max_streams_per_peer {
+            return error_job("Too many streams");
+        }
+        if pending + size > self.max_pending_bytes_per_peer {
+            return error_job("Too many bytes pending");
+        }
+
+        // Even if the content was already received, it is received again rather than telling
+        // the peer which content we hold.
+        let path = dir.join(format!("{}.{}.part", id, peer_id.to_base58()));
+        let part = Arc::new(Mutex::new(PartFile {
+            stream: ReceivedStream {
+                peer_id,
+                id: id.clone(),
+                name,
+                path: dir.join(&id),
+                size,
+            },
+            path: path.clone(),
+            file: None,
+            hasher: Sha256::new(),
+            received: 0,
+            done: false,
+        }));
+        self.streams.insert(
+            id,
+            PartialStream {
+                peer_id,
+                size,
+                path,
+                part: part.clone(),
+                last_activity: now,
+            },
+        );
+        Box::new(move || {
+            if let Some(active) = cleanup {
+                remove_stale_parts(&dir, &active, ttl);
+            }
+            lock(&part)
+                .progress()
+                .unwrap_or_else(|err| StreamOutcome::error(err.to_string()))
+        })
+    }
+
+    fn write_chunk(&mut self, peer_id: &PeerId, id: &str, offset: u64, data: Vec<u8>) -> StreamJob {
+        let part = match self.streams.get_mut(id) {
+            Some(stream) if stream.peer_id == *peer_id => {
+                stream.last_activity = Instant::now();
+                stream.part.clone()
+            }
+            _ => return error_job("Unknown stream"),
+        };
+        Box::new(move || {
+            lock(&part)
+                .write(offset, &data)
+                .unwrap_or_else(|err| StreamOutcome::error(err.to_string()))
+        })
+    }
+
+    /// Drop the streams idle for the partial TTL. Returns the partial files still in use if the
+    /// directory is due for a scan of stale partial files.
+    fn expire(&mut self, now: Instant) -> Option<HashSet<PathBuf>> {
+        let ttl = self.partial_ttl;
+        self.streams
+            .retain(|_, stream| now.duration_since(stream.last_activity) < ttl);
+        if matches!(self.last_cleanup, Some(last) if now.duration_since(last) < CLEANUP_INTERVAL) {
+            return None;
+        }
+        self.last_cleanup = Some(now);
+        Some(self.streams.values().map(|stream| stream.path.clone()).collect())
+    }
+}
+
+fn lock(part: &Mutex<PartFile>) -> MutexGuard<'_, PartFile> {
+    part.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Remove the partial files of `dir` which aren't `active` and weren't modified for `ttl`.
+fn remove_stale_parts(dir: &Path, active: &HashSet<PathBuf>, ttl: Duration) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension() != Some("part".as_ref()) || active.contains(&path) {
+            continue;
+        }
+        let idle = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if idle.is_some_and(|idle| idle >= ttl) {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams(dir: &Path) -> IncomingStreams {
+        IncomingStreams::new(Some(StreamConfig {
+            dir: dir.to_path_buf(),
+            ..StreamConfig::default()
+        }))
+    }
+
+    fn run(streams: &mut IncomingStreams, peer: PeerId, request: StreamRequest) -> StreamResponse {
+        streams.handle(peer, request)().response
+    }
+
+    fn open(id: &str, size: u64) -> StreamRequest {
+        StreamRequest::Open {
+            id: id.to_string(),
+            name: "content".to_string(),
+            size,
+        }
+    }
+
+    fn chunk(id: &str, offset: u64, data: &[u8]) -> StreamRequest {
+        StreamRequest::Chunk {
+            id: id.to_string(),
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tinyp2p-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn interrupted_stream_is_resumed() {
+        let dir = temp_dir("stream-resume");
+        let (peer, content) = (PeerId::random(), b"the content of the stream".to_vec());
+        let (id, size) = digest(&mut content.as_slice()).unwrap();
+
+        let mut incoming = streams(&dir);
+        assert!(matches!(run(&mut incoming, peer, open(&id, size)), StreamResponse::Ack { offset: 0 }));
+        assert!(matches!(run(&mut incoming, peer, chunk(&id, 0, &content[..10])), StreamResponse::Ack { offset: 10 }));
+
+        // After a restart, the partial file is hashed again and the sender continues from its end.
+        let mut incoming = streams(&dir);
+        assert!(matches!(run(&mut incoming, PeerId::random(), open(&id, size)), StreamResponse::Ack { offset: 0 }));
+        let mut incoming = streams(&dir);
+        assert!(matches!(run(&mut incoming, peer, open(&id, size)), StreamResponse::Ack { offset: 10 }));
+        // A retried chunk only tells where to continue from.
+        assert!(matches!(run(&mut incoming, peer, chunk(&id, 0, &content[..10])), StreamResponse::Ack { offset: 10 }));
+        assert!(matches!(run(&mut incoming, peer, chunk(&id, 10, &content[10..])), StreamResponse::Complete));
+
+        assert_eq!(fs::read(dir.join(&id)).unwrap(), content);
+        assert!(!dir.join(format!("{}.{}.part", id, peer.to_base58())).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stream_with_another_digest_is_dropped() {
+        let dir = temp_dir("stream-mismatch");
+        let peer = PeerId::random();
+        let (id, size) = digest(&mut b"the announced content".as_slice()).unwrap();
+
+        let mut incoming = streams(&dir);
+        assert!(matches!(run(&mut incoming, peer, open(&id, size)), StreamResponse::Ack { offset: 0 }));
+        let response = run(&mut incoming, peer, chunk(&id, 0, &vec![0; size as usize]));
+        assert!(matches!(&response, StreamResponse::Error(reason) if reason == "Stream digest mismatch"));
+        incoming.settle(&peer, &id, &response);
+
+        assert!(!dir.join(&id).exists());
+        assert!(!dir.join(format!("{}.{}.part", id, peer.to_base58())).exists());
+        assert!(matches!(run(&mut incoming, peer, chunk(&id, 0, b"more")), StreamResponse::Error(_)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use crate::config::RateLimit;
+
+/// The number of per-peer buckets kept, idle ones are pruned and then the least recently used is evicted.
+const MAX_TRACKED_PEERS: usize = 1024;
+/// The minimum interval between two prunes, so that a map of active peers isn't scanned on every new peer.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket which is continuously refilled at `per_second` tokens per second.
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second as f64).min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Gives back a token which was acquired for a request that isn't accepted after all.
+    fn release(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.limit.burst as f64);
+    }
+
+    /// Whether the bucket would be full at `now`, without refilling it so that `last_refill` still tells when it was last used.
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * self.limit.per_second as f64 >= self.limit.burst as f64
+    }
+}
+
+/// Rate limiter of inbound requests, applied per peer and across all peers.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    peer_limit: Option<RateLimit>,
+    peers: HashMap<PeerId, TokenBucket>,
+    global: Option<TokenBucket>,
+    last_prune: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(peer_limit: Option<RateLimit>, global_limit: Option<RateLimit>) -> Self {
+        Self {
+            peer_limit,
+            peers: HashMap::new(),
+            global: global_limit.map(|limit| TokenBucket::new(limit, Instant::now())),
+            last_prune: None,
+        }
+    }
+
+    /// Take a token for a request from `peer`, returns `false` if the request should be throttled.
+    pub fn check(&mut self, peer: &PeerId) -> bool {
+        self.check_at(peer, Instant::now())
+    }
+
+    fn check_at(&mut self, peer: &PeerId, now: Instant) -> bool {
+        // The peer's own bucket is checked first, so that a flooding peer
+        // doesn't drain the global bucket once it is throttled.
+        if let Some(limit) = self.peer_limit {
+            if self.peers.len() >= MAX_TRACKED_PEERS && !self.peers.contains_key(peer) {
+                self.make_room(now);
+            }
+            let bucket = self
+                .peers
+                .entry(*peer)
+                .or_insert_with(|| TokenBucket::new(limit, now));
+            if !bucket.try_acquire(now) {
+                return false;
+            }
+        }
+
+        // A request throttled by the global bucket doesn't count against the peer.
+        if let Some(bucket) = self.global.as_mut() {
+            if !bucket.try_acquire(now) {
+                if let Some(bucket) = self.peers.get_mut(peer) {
+                    bucket.release();
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        // Buckets that are full again behave exactly like fresh ones, so they can be dropped.
+        if !matches!(self.last_prune, Some(last) if now.duration_since(last) < PRUNE_INTERVAL) {
+            self.last_prune = Some(now);
+            self.peers.retain(|_, bucket| !bucket.is_full_at(now));
+        }
+        if self.peers.len() >= MAX_TRACKED_PEERS {
+            let least_recent = self
+                .peers
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.last_refill)
+                .map(|(peer, _)| *peer);
+            if let Some(peer) = least_recent {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(burst: u32, per_second: u32) -> Option<RateLimit> {
+        Some(RateLimit { burst, per_second })
+    }
+
+    #[test]
+    fn buckets_are_refilled() {
+        let mut limiter = RateLimiter::new(limit(2, 1), None);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        assert!(limiter.check_at(&peer, now));
+        assert!(limiter.check_at(&peer, now));
+        assert!(!limiter.check_at(&peer, now));
+        // Other peers have their own bucket.
+        assert!(limiter.check_at(&PeerId::random(), now));
+
+        assert!(!limiter.check_at(&peer, now + Duration::from_millis(500)));
+        assert!(limiter.check_at(&peer, now + Duration::from_secs(1)));
+        assert!(!limiter.check_at(&peer, now + Duration::from_secs(1)));
+        // The bucket never holds more than a burst.
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.check_at(&peer, later));
+        assert!(limiter.check_at(&peer, later));
+        assert!(!limiter.check_at(&peer, later));
+    }
+
+    #[test]
+    fn global_throttling_keeps_the_peer_token() {
+        let mut limiter = RateLimiter::new(limit(1, 1), limit(1, 1));
+        let (peer, other, now) = (PeerId::random(), PeerId::random(), Instant::now());
+        assert!(limiter.check_at(&other, now));
+        assert!(!limiter.check_at(&peer, now));
+
+        // Once the global bucket is refilled, the peer still has its token.
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.check_at(&peer, later));
+        assert!(!limiter.check_at(&other, later));
+    }
+
+    #[test]
+    fn idle_peers_are_evicted() {
+        let mut limiter = RateLimiter::new(limit(1, 1), None);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        assert!(limiter.check_at(&peer, now));
+        for i in 1..MAX_TRACKED_PEERS {
+            assert!(limiter.check_at(&PeerId::random(), now + Duration::from_micros(i as u64)));
+        }
+        assert_eq!(limiter.peers.len(), MAX_TRACKED_PEERS);
+
+        // No bucket is full again yet, so the least recently used one is evicted.
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.check_at(&PeerId::random(), later));
+        assert_eq!(limiter.peers.len(), MAX_TRACKED_PEERS);
+        assert!(!limiter.peers.contains_key(&peer));
+
+        // Once the buckets are full again, they are all pruned.
+        assert!(limiter.check_at(&PeerId::random(), later + PRUNE_INTERVAL * 2));
+        assert_eq!(limiter.peers.len(), 1);
+    }
+}
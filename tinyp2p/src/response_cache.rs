@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use crate::protocol::ResponseType;
+
+/// The number of responses kept before the oldest ones are evicted.
+const MAX_ENTRIES: usize = 1024;
+
+/// The number of responses kept for a single peer, so that one peer can't evict the responses of the others.
+const MAX_ENTRIES_PER_PEER: usize = 64;
+
+/// The total size in bytes of the responses kept, larger responses aren't cached.
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// How long a response is kept for the retries of its request.
+const TTL: Duration = Duration::from_secs(300);
+
+/// A request is identified by its sender, its protocol and its idempotency key.
+type Key = (PeerId, String, String);
+
+#[derive(Debug)]
+struct Entry {
+    expires: Instant,
+    response: ResponseType,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.response.as_ref().map_or(0, Vec::len)
+    }
+}
+
+/// The responses kept for a peer.
+#[derive(Debug, Default)]
+struct Usage {
+    entries: usize,
+    bytes: usize,
+}
+
+/// Cache of the responses to requests with an idempotency key, so that a retried request
+/// gets the response to its first attempt instead of being handled again.
+/// Once full, the oldest response of the peer with the most responses, or bytes, is evicted first.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    entries: HashMap<Key, Entry>,
+    usage: HashMap<PeerId, Usage>,
+    bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn get(&mut self, peer: &PeerId, protocol: &str, idempotency_key: &str) -> Option<ResponseType> {
+        self.get_at(peer, protocol, idempotency_key, Instant::now())
+    }
+
+    fn get_at(&mut self, peer: &PeerId, protocol: &str, idempotency_key: &str, now: Instant) -> Option<ResponseType> {
+        let key = (*peer, protocol.to_string(), idempotency_key.to_string());
+        match self.entries.get(&key) {
+            Some(entry) if entry.expires > now => Some(entry.response.clone()),
+            Some(_) => {
+                self.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, peer: PeerId, protocol: String, idempotency_key: String, response: ResponseType) {
+        self.insert_at(peer, protocol, idempotency_key, response, Instant::now())
+    }
+
+    fn insert_at(
+        &mut self,
+        peer: PeerId,
+        protocol: String,
+        idempotency_key: String,
+        response: ResponseType,
+        now: Instant,
+    ) {
+        let entry = Entry {
+            expires: now + TTL,
+            response,
+        };
+        let size = entry.size();
+        if size > MAX_BYTES {
+            return;
+        }
+        let key = (peer, protocol, idempotency_key);
+        self.remove(&key);
+
+        let is_full = |cache: &Self| {
+            cache.entries.len() >= MAX_ENTRIES
+                || cache.bytes + size > MAX_BYTES
+                || cache.usage.get(&peer).is_some_and(|usage| usage.entries >= MAX_ENTRIES_PER_PEER)
+        };
+        if is_full(self) {
+            self.remove_expired(now);
+        }
+        while is_full(self) {
+            let full_peer = match self.usage.get(&peer) {
+                Some(usage) if usage.entries >= MAX_ENTRIES_PER_PEER => Some(peer),
+                _ if self.bytes + size > MAX_BYTES => {
+                    self.usage.iter().max_by_key(|(_, usage)| usage.bytes).map(|(peer, _)| *peer)
+                }
+                _ => self.usage.iter().max_by_key(|(_, usage)| usage.entries).map(|(peer, _)| *peer),
+            };
+            let oldest = full_peer.and_then(|full_peer| {
+                self.entries
+                    .iter()
+                    .filter(|((entry_peer, _, _), _)| *entry_peer == full_peer)
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone())
+            });
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => return,
+            }
+        }
+
+        let usage = self.usage.entry(peer).or_default();
+        usage.entries += 1;
+        usage.bytes += size;
+        self.bytes += size;
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &Key) {
+        let Some(entry) = self.entries.remove(key) else {
+            return;
+        };
+        let size = entry.size();
+        self.bytes -= size;
+        if let Some(usage) = self.usage.get_mut(&key.0) {
+            usage.entries -= 1;
+            usage.bytes -= size;
+            if usage.entries == 0 {
+                self.usage.remove(&key.0);
+            }
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expired = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &expired {
+            self.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ResponseError;
+
+    const PROTOCOL: &str = "protocol";
+
+    fn insert(cache: &mut ResponseCache, peer: PeerId, key: usize, response: ResponseType, now: Instant) {
+        cache.insert_at(peer, PROTOCOL.to_string(), key.to_string(), response, now);
+    }
+
+    fn contains(cache: &mut ResponseCache, peer: &PeerId, key: usize, now: Instant) -> bool {
+        cache.get_at(peer, PROTOCOL, &key.to_string(), now).is_some()
+    }
+
+    #[test]
+    fn responses_expire() {
+        let (mut cache, peer, now) = (ResponseCache::default(), PeerId::random(), Instant::now());
+        insert(&mut cache, peer, 0, Ok(b"response".to_vec()), now);
+        insert(&mut cache, peer, 1, Err(ResponseError::Throttled), now);
+
+        assert_eq!(cache.get_at(&peer, PROTOCOL, "0", now), Some(Ok(b"response".to_vec())));
+        assert_eq!(cache.get_at(&peer, PROTOCOL, "1", now), Some(Err(ResponseError::Throttled)));
+        assert!(!contains(&mut cache, &PeerId::random(), 0, now));
+        assert!(!contains(&mut cache, &peer, 0, now + TTL));
+        assert_eq!((cache.entries.len(), cache.bytes), (1, 0));
+    }
+
+    #[test]
+    fn a_peer_only_evicts_its_own_responses() {
+        let (mut cache, now) = (ResponseCache::default(), Instant::now());
+        let (peer, flooder) = (PeerId::random(), PeerId::random());
+        insert(&mut cache, peer, 0, Ok(Vec::new()), now);
+        for key in 0..MAX_ENTRIES * 2 {
+            insert(&mut cache, flooder, key, Ok(Vec::new()), now + Duration::from_millis(key as u64));
+        }
+
+        assert!(contains(&mut cache, &peer, 0, now));
+        assert_eq!(cache.usage[&flooder].entries, MAX_ENTRIES_PER_PEER);
+        // The oldest responses of the flooder were evicted.
+        assert!(!contains(&mut cache, &flooder, 0, now));
+        assert!(contains(&mut cache, &flooder, MAX_ENTRIES * 2 - 1, now));
+    }
+
+    #[test]
+    fn the_largest_peer_is_evicted_first() {
+        let (mut cache, now) = (ResponseCache::default(), Instant::now());
+        let (peer, flooder) = (PeerId::random(), PeerId::random());
+        let large = vec![0; MAX_BYTES / 4];
+        insert(&mut cache, peer, 0, Ok(vec![0; 16]), now);
+        for key in 0..8 {
+            insert(&mut cache, flooder, key, Ok(large.clone()), now + Duration::from_millis(key as u64));
+        }
+
+        assert!(cache.bytes <= MAX_BYTES);
+        assert!(contains(&mut cache, &peer, 0, now));
+        assert!(!contains(&mut cache, &flooder, 0, now));
+        assert!(contains(&mut cache, &flooder, 7, now));
+
+        // A response larger than the whole cache isn't kept.
+        insert(&mut cache, peer, 1, Ok(vec![0; MAX_BYTES + 1]), now);
+        assert!(!contains(&mut cache, &peer, 1, now));
+    }
+
+    #[test]
+    fn many_peers_are_bounded() {
+        let (mut cache, now) = (ResponseCache::default(), Instant::now());
+        for key in 0..MAX_ENTRIES + 1 {
+            insert(&mut cache, PeerId::random(), key, Ok(Vec::new()), now);
+        }
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert_eq!(cache.usage.len(), MAX_ENTRIES);
+    }
+}
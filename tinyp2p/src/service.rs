@@ -9,21 +9,34 @@
 //! - `EventHandler`: the trait that defines how to handle requests / broadcast-messages from remote peers.
 //!   The application should implement this trait and pass it to the `Server`.
 
-use std::{cell::OnceCell, collections::HashMap, fmt::Debug, io, time::Duration};
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    io::{self, Read, Seek, SeekFrom},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use itertools::Itertools;
 use libp2p::{
+    core::{transport::ListenerId, ConnectedPoint},
     futures::prelude::*,
-    gossipsub::{self, TopicHash},
+    gossipsub::{self, MessageAcceptance, MessageId, TopicHash},
     identify,
-    identity::ed25519,
+    identity::{ed25519, Keypair},
+    metrics::Recorder,
     ping,
-    request_response::{self, OutboundFailure, RequestId, ResponseChannel},
-    swarm::{SwarmBuilder, SwarmEvent},
+    request_response::{self, OutboundFailure, ProtocolSupport, RequestId, ResponseChannel},
+    swarm::{ConnectionId, ListenError, SwarmBuilder, SwarmEvent},
     Multiaddr, PeerId, Swarm,
 };
 use libp2p::swarm::DialError;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use tokio::{
     select,
     sync::{
@@ -33,7 +46,9 @@ use tokio::{
     time::{self, Interval},
 };
 
-use crate::{config::P2pConfig, PeerIdWithMultiaddr, protocol::*, transport,
+use serde::Serialize;
+
+use crate::{bandwidth::{BandwidthMeter, BandwidthReport}, config::{KeyType, KeystoreConfig, P2pConfig, ReqRespConfig, TopicAcl}, control::{self, ControlServer}, external_addr::ExternalAddrs, keys, metrics::{self, Direction, Metrics}, PeerIdWithMultiaddr, protocol::*, rate_limit::RateLimiter, response_cache::ResponseCache, transport,
             error::{P2pError, P2pSetBootNodeSuccessTypes, P2pSetBootNodeSuccessTypes::{BootNodeInvalid, BootNodeNotRequired, BootNodeSet}}};
 
 /// `EventHandler` is the trait that defines how to handle requests / broadcast-messages from remote peers.
@@ -43,23 +58,204 @@ pub trait EventHandler: Debug + Send + 'static {
 
     fn handle_listener_closed(&self, addrs: Vec<Multiaddr>) {}
 
-    fn handle_identify(&self, peer_id: &PeerId, addrs: Vec<Multiaddr>) {}
+    /// Handles the identify information received from a remote peer, its listen addresses are added to the DHT.
+    fn handle_identify(&self, peer_id: &PeerId, info: &PeerInfo) {}
     
     fn handle_remove_peer(&self, peer_id: &PeerId) {}
+
+    /// Handles the first connection established with a remote peer.
+    fn handle_peer_connected(&self, peer_id: &PeerId, connection: &ConnectionInfo) {}
+
+    /// Handles the last connection with a remote peer being closed, with its `cause` if it failed.
+    fn handle_peer_disconnected(&self, peer_id: &PeerId, connection: &ConnectionInfo, cause: Option<String>) {}
+
+    /// Handles a dial attempt, to an unknown peer if `peer_id` is `None`.
+    fn handle_dialing(&self, peer_id: Option<&PeerId>) {}
+
+    /// Handles a failed dial attempt.
+    fn handle_dial_failure(&self, peer_id: Option<&PeerId>, error: &DialError) {}
+
+    /// Handles an inbound connection which failed before being established.
+    fn handle_incoming_connection_error(&self, send_back_addr: &Multiaddr, error: &ListenError) {}
+
+    /// Handles a listen address which is no longer valid.
+    fn handle_expired_listen_addr(&self, addr: &Multiaddr) {}
+
+    /// Handles an external address which is confirmed, and advertised to peers.
+    fn handle_new_external_addr(&self, addr: &Multiaddr) {}
+
+    /// Handles an external address which is no longer confirmed.
+    fn handle_expired_external_addr(&self, addr: &Multiaddr) {}
     
     /// Handles an inbound request from a remote peer.
     fn handle_inbound_request(&self, request: Vec<u8>) -> Result<Vec<u8>, P2pError> {Ok(request)}
 
+    /// Handles an inbound request on a protocol without its own `RequestHandler`.
+    /// Requests on the default protocol are passed to `handle_inbound_request`.
+    fn handle_request(&self, ctx: &RequestContext, request: Vec<u8>) -> Result<Vec<u8>, P2pError> {
+        if ctx.protocol == DEFAULT_PROTOCOL {
+            self.handle_inbound_request(request)
+        } else {
+            Err(P2pError::UnsupportedProtocol(ctx.protocol.clone()))
+        }
+    }
+
+    /// Handles a remote peer whose inbound request was throttled by the rate limiter.
+    fn handle_throttled_peer(&self, peer_id: &PeerId) {}
+
     fn handle_outbound_failure(&self, error: OutboundFailure) {}
     fn handle_inbound_response(&self, response: ResponseType) {}
 
+    /// Validates a broadcast message before it is handled and propagated to other peers.
+    /// `Reject` penalizes the peer which propagated the message, `Ignore` silently drops it.
+    /// Broadcast requests are validated too, on their `<topic>/request` topic and without their envelope.
+    fn validate_broadcast(&self, topic: &str, source: Option<&PeerId>, message: &[u8]) -> MessageAcceptance {
+        MessageAcceptance::Accept
+    }
+
+    /// Handles a broadcast message rejected because its source isn't allowed to publish on the topic.
+    fn handle_unauthorized_broadcast(&self, topic: &str, source: Option<&PeerId>) {}
+
     /// Handles an broadcast message from a remote peer.
     fn handle_inbound_broadcast(&self, topic: String, message: Vec<u8>) {}
+
+    /// Handles a request broadcast on `topic` by `source`, the reply is sent back to it directly.
+    /// Returns `None` to not reply.
+    fn handle_broadcast_request(&self, topic: &str, source: &PeerId, request: Vec<u8>) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Decides whether to follow a peer which rotated its key, on the first handover of its old `PeerId`.
+    /// Returns true to move the topic ACLs, the routing table and the boot node to its new `PeerId`,
+    /// other state, like allow-lists, is up to the application.
+    /// A compromised old key can sign a handover too, so handovers aren't followed by default.
+    fn handle_key_handover(&self, handover: &KeyHandover) -> bool {
+        false
+    }
+
+    /// Handles a handover of an old `PeerId` to another key than its first handover, so the old key is compromised.
+    /// It isn't followed, reverting the first one if it was followed is up to the application.
+    fn handle_key_handover_conflict(&self, first: &KeyHandover, conflicting: &KeyHandover) {}
+
+    /// Handles the progress of an inbound stream, `received` out of `size` bytes.
+    fn handle_stream_progress(&self, peer_id: &PeerId, id: &str, received: u64, size: u64) {}
+}
+
+/// `RequestHandler` handles the inbound requests of a single named request-response protocol.
+pub trait RequestHandler: Send + 'static {
+    fn handle_request(&self, ctx: &RequestContext, request: Vec<u8>) -> Result<Vec<u8>, P2pError>;
+}
+
+impl<F> RequestHandler for F
+    where
+        F: Fn(&RequestContext, Vec<u8>) -> Result<Vec<u8>, P2pError> + Send + 'static,
+{
+    fn handle_request(&self, ctx: &RequestContext, request: Vec<u8>) -> Result<Vec<u8>, P2pError> {
+        self(ctx, request)
+    }
+}
+
+/// The context of an inbound request.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    /// The peer which sent the request.
+    pub peer_id: PeerId,
+    /// The name of the protocol the request was sent on.
+    pub protocol: String,
+    /// The wire format version negotiated with the peer.
+    pub version: ProtocolVersion,
+    /// The key identifying the request across retries, if the sender set one.
+    pub idempotency_key: Option<String>,
+}
+
+/// The options of an outbound request.
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+    /// The timeout of each attempt, the `request_timeout` of the protocol if not set.
+    /// It can't exceed the longest `request_timeout` of all protocols, which times the attempt out first.
+    pub timeout: Option<Duration>,
+    /// The number of retries after a `DialFailure` or a `ConnectionClosed`.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for every following one.
+    pub backoff: Duration,
+    /// The key identifying the request across retries, so that the remote peer handles it at most once.
+    /// A timed out request is only retried if it is set, since the remote peer may have handled it.
+    pub idempotency_key: Option<String>,
+}
+
+/// The number of successful responses `Client::request_many` waits for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quorum {
+    /// Every peer.
+    All,
+    /// More than half of the peers.
+    Majority,
+    /// A fixed number of peers.
+    N(usize),
+}
+
+impl Quorum {
+    fn required(&self, peers: usize) -> usize {
+        match self {
+            Quorum::All => peers,
+            Quorum::Majority => peers / 2 + 1,
+            Quorum::N(n) => *n,
+        }
+    }
+}
+
+/// The replies to a request sent with `Client::broadcast_request`, ends once its timeout expired.
+#[derive(Debug)]
+pub struct BroadcastReplies {
+    receiver: UnboundedReceiver<(PeerId, Vec<u8>)>,
+}
+
+impl Iterator for BroadcastReplies {
+    type Item = (PeerId, Vec<u8>);
+
+    /// Block until the next reply.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.blocking_recv()
+    }
+}
+
+/// A handle to an outbound request sent with `Client::send_request`.
+#[derive(Debug)]
+pub struct RequestHandle {
+    id: u64,
+    protocol: String,
+    receiver: oneshot::Receiver<ResponseType>,
+    cmd_sender: UnboundedSender<Command>,
+}
+
+impl RequestHandle {
+    /// The ID of the request, which can be passed to `Client::cancel_request`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Block until the response is received, or the request failed or was cancelled.
+    pub fn wait(self) -> Result<Vec<u8>, P2pError> {
+        match self.receiver.blocking_recv() {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(ResponseError::UnsupportedProtocol)) => Err(P2pError::UnsupportedProtocol(self.protocol)),
+            Ok(Err(err)) => Err(err.into()),
+            // The `Server` drops the responder when the request is cancelled.
+            Err(_) => Err(P2pError::RequestCancelled),
+        }
+    }
+
+    /// Cancel the request, a response received afterwards is ignored.
+    pub fn cancel(&self) {
+        let _ = self.cmd_sender.send(Command::CancelRequest(self.id));
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Client {
     cmd_sender: UnboundedSender<Command>,
+    /// The ID of the next outbound request, shared by all clones.
+    next_request_id: Arc<AtomicU64>,
 }
 
 pub struct Server<E: EventHandler> {
@@ -68,8 +264,14 @@ pub struct Server<E: EventHandler> {
 
     /// The local peer id.
     local_peer_id: PeerId,
+    /// The local keypair, signing the handover to a new keypair.
+    local_key: Keypair,
     /// The addresses that the server is listening on.
     listened_addresses: Vec<Multiaddr>,
+    /// The listeners added by the `Client`, collecting their addresses.
+    pending_listeners: HashMap<ListenerId, PendingListener>,
+    /// The candidate and configured external addresses.
+    external_addrs: ExternalAddrs,
     /// The receiver of commands from the client.
     cmd_receiver: UnboundedReceiver<Command>,
     /// The handler of events from remote peers.
@@ -79,27 +281,181 @@ pub struct Server<E: EventHandler> {
     /// The ticker to periodically discover new peers.
     discovery_ticker: Option<Interval>,
     /// The pending outbound requests, awaiting for a response from the remote.
-    pending_outbound_requests: HashMap<RequestId, oneshot::Sender<ResponseType>>,
+    pending_outbound_requests: HashMap<u64, PendingRequest>,
+    /// The pending scatter-gather requests, awaiting for a quorum of responses.
+    pending_gathers: HashMap<u64, PendingGather>,
+    /// The pending broadcast requests, awaiting for replies until their deadline.
+    pending_broadcast_requests: HashMap<u64, PendingBroadcastRequest>,
+    /// The ID of the next outbound request, shared with the `Client`.
+    next_request_id: Arc<AtomicU64>,
+    /// The outbound requests of the current attempts.
+    outbound_attempts: HashMap<RequestId, u64>,
+    /// The responses to inbound requests with an idempotency key.
+    response_cache: ResponseCache,
+    /// The named request-response protocols.
+    req_resp_protocols: HashMap<String, ProtocolSettings>,
+    /// The handlers of the named protocols, requests on other protocols go to the `EventHandler`.
+    request_handlers: HashMap<String, Box<dyn RequestHandler>>,
     /// The topics will be hashed when subscribing to the gossipsub protocol,
     /// but we need to keep the original topic names for broadcasting.
     pubsub_topics: Vec<String>,
+    /// The peers allowed to publish on a topic.
+    topic_acls: HashMap<String, TopicAcl>,
+    /// The first key handover of each peer.
+    key_handovers: KeyHandovers,
+    /// The established connections.
+    connections: HashMap<ConnectionId, ConnectionInfo>,
+    /// The round-trip time of the last ping to each connected peer.
+    ping_rtts: HashMap<PeerId, Duration>,
+    /// The identify information of the connected peers.
+    peer_infos: HashMap<PeerId, PeerInfo>,
+    /// The bytes sent and received, per peer and per protocol.
+    bandwidth: Arc<BandwidthMeter>,
+    metrics: Metrics,
+    /// The address to serve the metrics on, taken when the `Server` starts running.
+    metrics_addr: Option<SocketAddr>,
+    /// The control API to serve, only set by `new`, taken when the `Server` starts running.
+    control: Option<ControlServer>,
+    /// The pending outbound stream requests, awaiting for a response from the remote.
+    pending_stream_requests: HashMap<RequestId, oneshot::Sender<Result<StreamResponse, OutboundFailure>>>,
+    /// The inbound streams being received.
+    incoming_streams: IncomingStreams,
+    /// The results of the file I/O of inbound stream requests, run on the blocking thread pool.
+    stream_job_sender: UnboundedSender<StreamJobResult>,
+    stream_job_results: UnboundedReceiver<StreamJobResult>,
+    /// The completed streams which no `Client` is waiting for yet.
+    received_streams: VecDeque<ReceivedStream>,
+    /// The `Client`s waiting for a stream.
+    stream_waiters: VecDeque<oneshot::Sender<ReceivedStream>>,
 
     pub boot_node: Option<PeerIdWithMultiaddr>,
     boot_node_required: bool,
 }
 
+/// An outbound request awaiting its response.
+struct PendingRequest {
+    target: PeerId,
+    /// Kept to be sent again on retries.
+    request: Request,
+    options: RequestOptions,
+    /// The number of retries so far.
+    retries: u32,
+    /// The current attempt, `None` while waiting to retry.
+    attempt: Option<RequestId>,
+    /// The time after which the current attempt fails with `OutboundFailure::Timeout`,
+    /// or the time of the next retry.
+    deadline: time::Instant,
+    responder: Responder,
+}
+
+/// Where the response to an outbound request goes.
+enum Responder {
+    /// A `Client` waiting for this request only.
+    Client(oneshot::Sender<ResponseType>),
+    /// The scatter-gather request with the given ID.
+    Gather(u64),
+    /// Nobody, the response is only acknowledged.
+    Discard,
+}
+
+/// A broadcast request, awaiting for replies.
+struct PendingBroadcastRequest {
+    /// The time after which replies are no longer accepted.
+    deadline: time::Instant,
+    replies: UnboundedSender<(PeerId, Vec<u8>)>,
+}
+
+/// A request sent to many peers, awaiting for a quorum of responses.
+struct PendingGather {
+    /// The number of successful responses to wait for.
+    required: usize,
+    /// The number of requests which haven't succeeded or failed yet.
+    remaining: usize,
+    /// The IDs of the requests to each peer, cancelled once the gather is resolved.
+    requests: Vec<u64>,
+    responses: HashMap<PeerId, Vec<u8>>,
+    responder: oneshot::Sender<Result<HashMap<PeerId, Vec<u8>>, P2pError>>,
+}
+
+/// Where the addresses of a listener added by the `Client` go.
+type ListenResponder = oneshot::Sender<Result<(ListenerId, Vec<Multiaddr>), P2pError>>;
+
+/// A listener added by the `Client`, collecting the addresses it is bound to.
+struct PendingListener {
+    addrs: Vec<Multiaddr>,
+    /// The time the addresses are returned, `LISTEN_ADDRS_WINDOW` after the first one.
+    deadline: Option<time::Instant>,
+    responder: ListenResponder,
+}
+
+/// An inbound stream request whose file I/O is done, awaiting for its response to be sent.
+struct StreamJobResult {
+    peer: PeerId,
+    id: String,
+    channel: ResponseChannel<StreamResponse>,
+    outcome: StreamOutcome,
+}
+
+/// The settings of a named request-response protocol, enforced by the `Server`.
+struct ProtocolSettings {
+    support: ProtocolSupport,
+    request_timeout: Duration,
+    /// The rate limiter of inbound requests.
+    rate_limiter: RateLimiter,
+}
+
+impl ProtocolSettings {
+    fn new(config: Option<&ReqRespConfig>) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        Self {
+            support: config.support.unwrap_or(ProtocolSupport::Full),
+            request_timeout: config
+                .request_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            rate_limiter: RateLimiter::new(config.peer_rate_limit, config.global_rate_limit),
+        }
+    }
+}
+
+/// The maximum number of completed streams kept until a `Client` receives them.
+const MAX_RECEIVED_STREAMS: usize = 64;
+
+/// How long the addresses of a new listener are collected after the first one,
+/// as a listener on an unspecified IP address is bound to each interface in turn.
+const LISTEN_ADDRS_WINDOW: Duration = Duration::from_millis(100);
+
 /// Create a new secret key for the p2p node.
 pub fn new_secret_key() -> String {
     let secret = ed25519::SecretKey::generate();
     bs58::encode(secret.as_ref()).into_string()
 }
 
+/// Create a new secret key of the given type for the p2p node.
+pub fn new_secret_key_of(key_type: KeyType) -> String {
+    keys::encode_secret(&keys::generate(key_type)).expect("generated keys have a raw secret")
+}
+
 /// Create a new p2p node, which consists of a `Client` and a `Server`.
 pub fn new<E: EventHandler>(config: P2pConfig) -> Result<(Client, Server<E>), P2pError> {
     let (cmd_sender, cmd_receiver) = mpsc::unbounded_channel();
 
-    let server = Server::new(config, cmd_receiver)?;
-    let client = Client { cmd_sender };
+    let token = config
+        .control_token_file
+        .as_deref()
+        .map(control::read_token)
+        .transpose()?;
+    let control = config.control.clone();
+    let mut server = Server::new(config, cmd_receiver)?;
+    let client = Client {
+        cmd_sender,
+        next_request_id: server.next_request_id.clone(),
+    };
+    server.control = control.map(|endpoint| ControlServer {
+        endpoint,
+        token,
+        client: client.clone(),
+    });
 
     Ok((client, server))
 }
@@ -113,17 +469,162 @@ impl Client {
 
     /// Send a blocking request to the `target` peer.
     pub fn blocking_request(&self, target: &str, request: Vec<u8>) -> Result<Vec<u8>, P2pError> {
+        self.request_on(DEFAULT_PROTOCOL, target, request)
+    }
+
+    /// Send a blocking request to the `target` peer on the named `protocol`.
+    pub fn request_on(&self, protocol: &str, target: &str, request: Vec<u8>) -> Result<Vec<u8>, P2pError> {
+        self.send_request(protocol, target, request, RequestOptions::default())?
+            .wait()
+    }
+
+    /// Send a request to the `target` peer on the named `protocol`, without waiting for the response.
+    pub fn send_request(
+        &self,
+        protocol: &str,
+        target: &str,
+        request: Vec<u8>,
+        options: RequestOptions,
+    ) -> Result<RequestHandle, P2pError> {
         let target = target.parse().map_err(|_| P2pError::InvalidPeerId)?;
 
+        let id = self.next_request_id();
         let (responder, receiver) = oneshot::channel();
         let _ = self.cmd_sender.send(Command::SendRequest {
+            id,
             target,
+            request: Request {
+                protocol: protocol.to_string(),
+                version: ProtocolVersion::latest(),
+                idempotency_key: options.idempotency_key.clone(),
+                payload: request,
+            },
+            options,
+            responder,
+        });
+        Ok(RequestHandle {
+            id,
+            protocol: protocol.to_string(),
+            receiver,
+            cmd_sender: self.cmd_sender.clone(),
+        })
+    }
+
+    /// Send the request on `protocol` to all the `peers`, and block until a `quorum` of them responded
+    /// successfully, or it can't be reached anymore. Each request times out after `timeout`, which can't
+    /// exceed the longest `request_timeout` of all protocols.
+    /// Returns the successful responses, keyed by peer. If the quorum isn't reached, the responses
+    /// received so far are returned in the `QuorumNotReached` error.
+    pub fn request_many<T: AsRef<str>>(
+        &self,
+        protocol: &str,
+        peers: &[T],
+        request: Vec<u8>,
+        quorum: Quorum,
+        timeout: Duration,
+    ) -> Result<HashMap<PeerId, Vec<u8>>, P2pError> {
+        let targets = peers
+            .iter()
+            .map(|peer| peer.as_ref().parse().map_err(|_| P2pError::InvalidPeerId))
+            .collect::<Result<Vec<PeerId>, _>>()?
+            .into_iter()
+            .unique()
+            .map(|target| (self.next_request_id(), target))
+            .collect();
+
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::RequestMany {
+            id: self.next_request_id(),
+            targets,
+            request: Request {
+                protocol: protocol.to_string(),
+                version: ProtocolVersion::latest(),
+                idempotency_key: None,
+                payload: request,
+            },
+            quorum,
+            timeout,
+            responder,
+        });
+        receiver.blocking_recv()?
+    }
+
+    /// Publish a request to the subscribers of the given topic, and return their replies
+    /// received within `timeout`.
+    pub fn broadcast_request(
+        &self,
+        topic: impl Into<String>,
+        request: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<BroadcastReplies, P2pError> {
+        let (replies, receiver) = mpsc::unbounded_channel();
+        let (responder, published) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::BroadcastRequest {
+            id: self.next_request_id(),
+            topic: topic.into(),
             request,
+            timeout,
+            replies,
             responder,
         });
-        receiver
-            .blocking_recv()?
-            .map_err(|_| P2pError::RequestRejected)
+        published.blocking_recv()??;
+        Ok(BroadcastReplies { receiver })
+    }
+
+    /// Cancel the outbound request with the given ID.
+    pub fn cancel_request(&self, id: u64) {
+        let _ = self.cmd_sender.send(Command::CancelRequest(id));
+    }
+
+    /// Generate a new keypair of the same type as the local one, save it to the new encrypted key file `keystore`,
+    /// then broadcast a handover to it signed by both, so that peers move their state to the new `PeerId`.
+    /// The running node keeps its identity: restart it with the new key file.
+    /// If the broadcast fails, nothing was handed over and the new key file can be deleted.
+    pub fn rotate_key(&self, keystore: &KeystoreConfig) -> Result<(Keypair, KeyHandover), P2pError> {
+        let passphrase = keystore.passphrase()?;
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::NewKeyHandover(responder));
+        let (new_key, handover) = receiver.blocking_recv()??;
+        // The handover is only announced once the new key can't be lost.
+        crate::keystore::save_keypair(&keystore.path, &new_key, &passphrase)?;
+        self.publish(KEY_HANDOVER_TOPIC, handover.encode())?;
+        info!("📣 Handed over the local identity to {}", handover.new_peer_id());
+        Ok((new_key, handover))
+    }
+
+    /// Listen on another address, returns the ID of the listener and the addresses it is bound to.
+    /// A listener on an unspecified IP address returns the addresses bound shortly after its first one,
+    /// the addresses of interfaces which come up later are added to `NodeStatus::listened_addresses`.
+    pub fn listen_on(&self, addr: &str) -> Result<(ListenerId, Vec<Multiaddr>), P2pError> {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::ListenOn {
+            addr: addr.parse()?,
+            responder,
+        });
+        receiver.blocking_recv()?
+    }
+
+    /// Stop listening on the addresses of the listener, returns false if there is no such listener.
+    pub fn remove_listener(&self, id: ListenerId) -> bool {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::RemoveListener { id, responder });
+        receiver.blocking_recv().unwrap_or_default()
+    }
+
+    /// Subscribe to the given topic, its messages are passed to the `EventHandler`.
+    pub fn subscribe(&self, topic: impl Into<String>) -> Result<(), P2pError> {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::Subscribe {
+            topic: topic.into(),
+            responder,
+        });
+        receiver.blocking_recv()?
+    }
+
+    /// Subscribe to the broadcast requests on the given topic, they are passed to
+    /// `EventHandler::handle_broadcast_request`.
+    pub fn subscribe_requests(&self, topic: &str) -> Result<(), P2pError> {
+        self.subscribe(request_topic(topic))
     }
 
     /// Publish a message to the given topic.
@@ -134,6 +635,93 @@ impl Client {
         });
     }
 
+    /// Publish a message to the given topic, and block until it is handed to the peers,
+    /// failing e.g. with `InsufficientPeers` if no peer is subscribed to the topic.
+    pub fn publish(&self, topic: impl Into<String>, message: Vec<u8>) -> Result<(), P2pError> {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::Publish {
+            topic: topic.into(),
+            message,
+            responder,
+        });
+        receiver.blocking_recv()?
+    }
+
+    /// Send the content of `data` to the `target` peer as a stream of chunks.
+    /// `progress` is called with the bytes acknowledged by the receiver and the total size.
+    /// Sending the same content again resumes the stream from where the receiver stopped.
+    pub fn send_stream<R: Read + Seek>(
+        &self,
+        target: &str,
+        name: &str,
+        mut data: R,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), P2pError> {
+        let target = target.parse().map_err(|_| P2pError::InvalidPeerId)?;
+
+        data.seek(SeekFrom::Start(0))?;
+        let (id, size) = digest(&mut data)?;
+
+        let mut response = self.stream_request(
+            target,
+            StreamRequest::Open {
+                id: id.clone(),
+                name: name.to_string(),
+                size,
+            },
+        )?;
+        loop {
+            let offset = match response {
+                StreamResponse::Ack { offset } => offset,
+                StreamResponse::Complete => {
+                    progress(size, size);
+                    return Ok(());
+                }
+                StreamResponse::Error(reason) => return Err(P2pError::StreamError(reason)),
+            };
+            progress(offset, size);
+
+            data.seek(SeekFrom::Start(offset))?;
+            let mut chunk = Vec::with_capacity(MAX_CHUNK_SIZE);
+            data.by_ref().take(MAX_CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                return Err(P2pError::StreamError(format!("Unexpected end of data at {}", offset)));
+            }
+
+            response = self.stream_request(
+                target,
+                StreamRequest::Chunk {
+                    id: id.clone(),
+                    offset,
+                    data: chunk,
+                },
+            )?;
+        }
+    }
+
+    /// Wait for the next stream received from a remote peer.
+    pub fn receive_stream(&self) -> Result<ReceivedStream, P2pError> {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::ReceiveStream(responder));
+        Ok(receiver.blocking_recv()?)
+    }
+
+    fn stream_request(&self, target: PeerId, request: StreamRequest) -> Result<StreamResponse, P2pError> {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::SendStreamRequest {
+            target,
+            request,
+            responder,
+        });
+        receiver
+            .blocking_recv()?
+            .map_err(|err| P2pError::StreamError(err.to_string()))
+    }
+
+    fn next_request_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
     /// Get status of the node for debugging.
     pub fn get_node_status(&self) -> NodeStatus {
         let (responder, receiver) = oneshot::channel();
@@ -141,6 +729,25 @@ impl Client {
         receiver.blocking_recv().unwrap_or_default()
     }
 
+    /// Get the bandwidth used by the node, in total, per peer and per protocol.
+    pub fn get_bandwidth(&self) -> BandwidthReport {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::GetBandwidth(responder));
+        receiver.blocking_recv().unwrap_or_default()
+    }
+
+    /// Reset the bytes counted against the peer quotas, so that peers over their quota are accepted again.
+    pub fn reset_bandwidth_quotas(&self) {
+        let _ = self.cmd_sender.send(Command::ResetBandwidthQuotas);
+    }
+
+    /// Get the pub/sub scores of the known peers, empty if peer scoring is disabled.
+    pub fn get_peer_scores(&self) -> HashMap<PeerId, f64> {
+        let (responder, receiver) = oneshot::channel();
+        let _ = self.cmd_sender.send(Command::GetPeerScores(responder));
+        receiver.blocking_recv().unwrap_or_default()
+    }
+
     /// Get known peers of the node.
     pub fn get_known_peers(&self) -> Vec<String> {
         self.get_node_status()
@@ -158,15 +765,62 @@ pub enum Command {
         responder: oneshot::Sender<Result<P2pSetBootNodeSuccessTypes, DialError>>
     },
     SendRequest {
+        id: u64,
         target: PeerId,
-        request: Vec<u8>,
+        request: Request,
+        options: RequestOptions,
         responder: oneshot::Sender<ResponseType>,
     },
+    CancelRequest(u64),
+    RequestMany {
+        id: u64,
+        /// The ID of the request to each target.
+        targets: Vec<(u64, PeerId)>,
+        request: Request,
+        quorum: Quorum,
+        timeout: Duration,
+        responder: oneshot::Sender<Result<HashMap<PeerId, Vec<u8>>, P2pError>>,
+    },
+    SendStreamRequest {
+        target: PeerId,
+        request: StreamRequest,
+        responder: oneshot::Sender<Result<StreamResponse, OutboundFailure>>,
+    },
+    ReceiveStream(oneshot::Sender<ReceivedStream>),
     Broadcast {
         topic: String,
         message: Vec<u8>,
     },
+    Publish {
+        topic: String,
+        message: Vec<u8>,
+        responder: oneshot::Sender<Result<(), P2pError>>,
+    },
+    Subscribe {
+        topic: String,
+        responder: oneshot::Sender<Result<(), P2pError>>,
+    },
+    ListenOn {
+        addr: Multiaddr,
+        responder: oneshot::Sender<Result<(ListenerId, Vec<Multiaddr>), P2pError>>,
+    },
+    RemoveListener {
+        id: ListenerId,
+        responder: oneshot::Sender<bool>,
+    },
+    NewKeyHandover(oneshot::Sender<Result<(Keypair, KeyHandover), P2pError>>),
+    BroadcastRequest {
+        id: u64,
+        topic: String,
+        request: Vec<u8>,
+        timeout: Duration,
+        replies: UnboundedSender<(PeerId, Vec<u8>)>,
+        responder: oneshot::Sender<Result<(), P2pError>>,
+    },
     GetStatus(oneshot::Sender<NodeStatus>),
+    GetPeerScores(oneshot::Sender<HashMap<PeerId, f64>>),
+    GetBandwidth(oneshot::Sender<BandwidthReport>),
+    ResetBandwidthQuotas,
 }
 
 impl<E: EventHandler> Server<E> {
@@ -175,32 +829,103 @@ impl<E: EventHandler> Server<E> {
         config: P2pConfig,
         cmd_receiver: UnboundedReceiver<Command>,
     ) -> Result<Self, P2pError> {
-        let addr: Multiaddr = config.addr.parse()?;
+        config.validate()?;
+        let listen_addrs = config
+            .listen_addrs
+            .iter()
+            .map(|addr| addr.parse())
+            .collect::<Result<Vec<Multiaddr>, _>>()?;
         let local_key = config.gen_keypair()?;
         let local_peer_id = local_key.public().to_peer_id();
         info!("📣 Local peer id: {local_peer_id:?}");
 
-        let pubsub_topics = config.pubsub_topics;
+        // The broadcast requests of a topic are received on its companion topic, if opted in,
+        // and every node follows the key handovers of its peers.
+        let pubsub_topics = config
+            .pubsub_topics
+            .iter()
+            .cloned()
+            .chain(config.request_topics.iter().map(|topic| request_topic(topic)))
+            .chain([KEY_HANDOVER_TOPIC.to_string()])
+            .unique()
+            .collect::<Vec<_>>();
+        let topic_acls = config
+            .pubsub
+            .as_ref()
+            .map(|pubsub| pubsub.topic_acls.clone())
+            .unwrap_or_default();
+        let key_handovers = KeyHandovers::load(config.key_handovers_file.clone())?;
+        let mut req_resp_protocols = HashMap::from([(
+            DEFAULT_PROTOCOL.to_string(),
+            ProtocolSettings::new(config.req_resp.as_ref()),
+        )]);
+        for (name, protocol) in &config.req_resp_protocols {
+            req_resp_protocols.insert(name.clone(), ProtocolSettings::new(Some(protocol)));
+        }
+        // The replies to broadcast requests follow the configuration of the default protocol.
+        req_resp_protocols.insert(
+            BROADCAST_REPLY_PROTOCOL.to_string(),
+            ProtocolSettings::new(config.req_resp.as_ref()),
+        );
+        let incoming_streams = IncomingStreams::new(config.stream);
+        let (stream_job_sender, stream_job_results) = mpsc::unbounded_channel();
         // Build the [swarm](https://docs.rs/libp2p/latest/libp2p/struct.Swarm.html)
+        let bandwidth = Arc::new(BandwidthMeter::new(config.bandwidth));
         let mut swarm = {
-            let transport = transport::build_transport(local_key.clone());
-            let behaviour = Behaviour::new(local_key, pubsub_topics.clone(), config.req_resp)?;
+            let transport = transport::build_transport(local_key.clone(), bandwidth.clone());
+            let behaviour = Behaviour::new(
+                local_key.clone(),
+                config.identify,
+                pubsub_topics.clone(),
+                config.pubsub,
+                config.req_resp,
+                &config.req_resp_protocols,
+            )?;
             SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build()
         };
-        // Switch to server mode.
-        swarm.add_external_address(addr.clone());
-        swarm.listen_on(addr)?;
+        let external_addrs = ExternalAddrs::new(config.external_addrs.as_ref());
+        for addr in external_addrs.public_addrs() {
+            swarm.add_external_address(addr.clone());
+        }
+        for addr in listen_addrs {
+            swarm.listen_on(addr)?;
+        }
 
         let mut out = Self {
             network_service: swarm,
             local_peer_id,
+            local_key,
             listened_addresses: Vec::new(),
+            pending_listeners: HashMap::new(),
+            external_addrs,
             cmd_receiver,
             event_handler: OnceCell::new(),
             discovery_interval: config.discovery_interval,
             discovery_ticker: None,
             pending_outbound_requests: HashMap::new(),
+            pending_gathers: HashMap::new(),
+            pending_broadcast_requests: HashMap::new(),
+            next_request_id: Arc::new(AtomicU64::new(0)),
+            outbound_attempts: HashMap::new(),
+            response_cache: ResponseCache::default(),
+            req_resp_protocols,
+            request_handlers: HashMap::new(),
             pubsub_topics,
+            topic_acls,
+            key_handovers,
+            connections: HashMap::new(),
+            ping_rtts: HashMap::new(),
+            peer_infos: HashMap::new(),
+            bandwidth,
+            metrics: Metrics::new(),
+            metrics_addr: config.metrics_addr,
+            control: None,
+            pending_stream_requests: HashMap::new(),
+            incoming_streams,
+            stream_job_sender,
+            stream_job_results,
+            received_streams: VecDeque::new(),
+            stream_waiters: VecDeque::new(),
             boot_node_required: config.boot_node_required,
             boot_node: None};
         let _ = out.set_boot_node(config.boot_node);
@@ -235,9 +960,25 @@ impl<E: EventHandler> Server<E> {
         self.event_handler.set(handler).unwrap();
     }
 
+    /// Set the handler of inbound requests on the named `protocol`.
+    pub fn set_request_handler(&mut self, protocol: impl Into<String>, handler: impl RequestHandler) {
+        self.request_handlers.insert(protocol.into(), Box::new(handler));
+    }
+
     /// Run the `Server`.
     pub async fn run(&mut self) {
+        if let Some(addr) = self.metrics_addr.take() {
+            tokio::spawn(metrics::serve(addr, self.metrics.registry()));
+        }
+        if let Some(control) = self.control.take() {
+            tokio::spawn(control::serve(control));
+        }
+
         loop {
+            self.metrics.set_pending_requests(self.pending_outbound_requests.len());
+            let request_deadline = self.next_request_deadline();
+            let external_addr_expiry = self.external_addrs.next_expiry();
+            let listen_addrs_deadline = self.pending_listeners.values().filter_map(|listener| listener.deadline).min();
             select! {
                 _ = async {
                     if let Some(ticker) = self.discovery_ticker.as_mut() {
@@ -247,6 +988,18 @@ impl<E: EventHandler> Server<E> {
                     self.network_service.behaviour_mut().discover_peers();
                 },
 
+                // The earliest pending request timed out.
+                _ = sleep_until(request_deadline) => self.expire_requests(),
+
+                // The earliest observed external address wasn't observed again in time.
+                _ = sleep_until(external_addr_expiry) => self.expire_external_addrs(),
+
+                // The addresses of a new listener were collected.
+                _ = sleep_until(listen_addrs_deadline) => self.return_listen_addrs(),
+
+                // The file I/O of an inbound stream request is done.
+                Some(result) = self.stream_job_results.recv() => self.handle_stream_job_result(result),
+
                 // Next command from the `Client`.
                 msg = self.cmd_receiver.recv() => {
                     if let Some(cmd) = msg {
@@ -267,49 +1020,228 @@ impl<E: EventHandler> Server<E> {
             Command::SetBootNode {boot_node, responder} =>
                 responder.send(self.set_boot_node(Some(boot_node))).unwrap(),
             Command::SendRequest {
+                id,
                 target,
                 request,
+                options,
+                responder,
+            } => self.handle_outbound_request(id, target, request, options, Responder::Client(responder)),
+            Command::CancelRequest(id) => self.cancel_request(id),
+            Command::RequestMany {
+                id,
+                targets,
+                request,
+                quorum,
+                timeout,
                 responder,
-            } => self.handle_outbound_request(target, request, responder),
-            Command::Broadcast { topic, message } => self.handle_outbound_broadcast(topic, message),
+            } => self.handle_request_many(id, targets, request, quorum, timeout, responder),
+            Command::SendStreamRequest {
+                target,
+                request,
+                responder,
+            } => {
+                let req_id = self
+                    .network_service
+                    .behaviour_mut()
+                    .send_stream_request(&target, request);
+                self.pending_stream_requests.insert(req_id, responder);
+            }
+            Command::ReceiveStream(responder) => self.handle_receive_stream(responder),
+            Command::Broadcast { topic, message } => {
+                if let Err(err) = self.handle_outbound_broadcast(topic, message) {
+                    warn!("❗ Failed to broadcast: {}", err);
+                }
+            }
+            Command::Publish {
+                topic,
+                message,
+                responder,
+            } => {
+                let _ = responder.send(self.handle_outbound_broadcast(topic, message));
+            }
+            Command::Subscribe { topic, responder } => {
+                let _ = responder.send(self.subscribe(topic));
+            }
+            Command::ListenOn { addr, responder } => match self.network_service.listen_on(addr) {
+                Ok(id) => {
+                    self.pending_listeners.insert(
+                        id,
+                        PendingListener {
+                            addrs: Vec::new(),
+                            deadline: None,
+                            responder,
+                        },
+                    );
+                }
+                Err(err) => {
+                    let _ = responder.send(Err(err.into()));
+                }
+            },
+            Command::RemoveListener { id, responder } => {
+                let _ = responder.send(self.network_service.remove_listener(id));
+            }
+            Command::NewKeyHandover(responder) => {
+                let _ = responder.send(self.new_key_handover());
+            }
+            Command::BroadcastRequest {
+                id,
+                topic,
+                request,
+                timeout,
+                replies,
+                responder,
+            } => {
+                let result = self.handle_outbound_broadcast_request(id, topic, request, timeout, replies);
+                let _ = responder.send(result);
+            }
             Command::GetStatus(responder) => responder.send(self.get_status()).unwrap(),
+            Command::GetPeerScores(responder) => {
+                let _ = responder.send(self.network_service.behaviour().peer_scores());
+            }
+            Command::GetBandwidth(responder) => {
+                let _ = responder.send(self.bandwidth.report());
+            }
+            Command::ResetBandwidthQuotas => self.bandwidth.reset_quotas(),
         }
     }
 
     // Process the next event coming from `Swarm`.
     fn handle_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent, BehaviourErr>) {
+        self.metrics.record(&event);
         let _ = match event {
             SwarmEvent::Behaviour(ev) => return self.handle_behaviour_event(ev),
 
-            SwarmEvent::NewListenAddr { address, .. } => {
+            SwarmEvent::NewListenAddr { listener_id, address } => {
                 if let Some(handler) = self.event_handler.get() {
                     handler.handle_new_listen_addr(&self.local_peer_id, &address);
                 }
+                if let Some(listener) = self.pending_listeners.get_mut(&listener_id) {
+                    listener.addrs.push(address.clone());
+                    listener
+                        .deadline
+                        .get_or_insert_with(|| time::Instant::now() + LISTEN_ADDRS_WINDOW);
+                }
+                if self.external_addrs.is_external_listen_addr(&address) {
+                    self.add_external_address(address);
+                }
                 self.update_listened_addresses();
                 return;
             }
 
             SwarmEvent::ListenerClosed {
-                reason, addresses, ..
-            } => return self.handle_listener_closed(reason, addresses),
+                listener_id,
+                reason,
+                addresses,
+            } => return self.handle_listener_closed(listener_id, reason, addresses),
 
-            // Can't connect to the `peer`, remove it from the DHT.
-            SwarmEvent::OutgoingConnectionError {
-                peer_id: Some(peer),
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                if let Some(handler) = self.event_handler.get() {
+                    handler.handle_expired_listen_addr(&address);
+                }
+                if self.external_addrs.is_external_listen_addr(&address) {
+                    self.remove_external_address(&address);
+                }
+                self.update_listened_addresses();
+                return;
+            }
+
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
                 ..
-            } => return self.remove_peer(&peer),
+            } => {
+                let connection = ConnectionInfo::new(peer_id, &endpoint);
+                debug!("☕ Connected to {} at {}", peer_id, connection.remote_address);
+                if let (Some(handler), 1) = (self.event_handler.get(), num_established.get()) {
+                    handler.handle_peer_connected(&peer_id, &connection);
+                }
+                self.connections.insert(connection_id, connection);
+                return;
+            }
+
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                cause,
+            } => {
+                self.connections.remove(&connection_id);
+                if num_established == 0 {
+                    self.ping_rtts.remove(&peer_id);
+                    self.peer_infos.remove(&peer_id);
+                    if let Some(handler) = self.event_handler.get() {
+                        let connection = ConnectionInfo::new(peer_id, &endpoint);
+                        handler.handle_peer_disconnected(&peer_id, &connection, cause.map(|err| err.to_string()));
+                    }
+                }
+                return;
+            }
+
+            SwarmEvent::Dialing { peer_id, .. } => {
+                if let Some(handler) = self.event_handler.get() {
+                    handler.handle_dialing(peer_id.as_ref());
+                }
+                return;
+            }
+
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => {
+                warn!("❗ Inbound connection from {} failed: {}", send_back_addr, error);
+                if let Some(handler) = self.event_handler.get() {
+                    handler.handle_incoming_connection_error(&send_back_addr, &error);
+                }
+                return;
+            }
+
+            // Can't connect to the `peer`, remove it from the DHT.
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                if let Some(handler) = self.event_handler.get() {
+                    handler.handle_dial_failure(peer_id.as_ref(), &error);
+                }
+                if let Some(peer) = peer_id {
+                    self.remove_peer(&peer);
+                }
+                return;
+            }
 
             _ => return,
         };
     }
 
     fn handle_behaviour_event(&mut self, ev: BehaviourEvent) {
+        match &ev {
+            BehaviourEvent::Kad(event) => self.metrics.record(event),
+            BehaviourEvent::Identify(event) => self.metrics.record(event),
+            BehaviourEvent::Ping(event) => self.metrics.record(event),
+            BehaviourEvent::Pubsub(event) => self.metrics.record(event),
+            _ => {}
+        }
+
         match ev {
             // See https://docs.rs/libp2p/latest/libp2p/kad/index.html#important-discrepancies
-            BehaviourEvent::Identify(identify::Event::Received {
-                                         peer_id,
-                                         info: identify::Info { listen_addrs, .. },
-                                     }) => self.add_addresses(&peer_id, listen_addrs),
+            BehaviourEvent::Identify(identify::Event::Received { peer_id, info }) => {
+                let info = PeerInfo::from(info);
+                if let Some(handler) = self.event_handler.get() {
+                    handler.handle_identify(&peer_id, &info);
+                }
+                self.add_addresses(&peer_id, info.listen_addrs.clone());
+                self.observe_addr(peer_id, &info.observed_addr);
+                self.peer_infos.insert(peer_id, info);
+            }
+
+            BehaviourEvent::Ping(ping::Event {
+                                     peer,
+                                     result: Ok(rtt),
+                                     ..
+                                 }) => {
+                self.ping_rtts.insert(peer, rtt);
+            }
 
             // The remote peer is unreachable, remove it from the DHT.
             BehaviourEvent::Ping(ping::Event {
@@ -319,12 +1251,12 @@ impl<E: EventHandler> Server<E> {
                                  }) => self.remove_peer(&peer),
 
             BehaviourEvent::ReqResp(request_response::Event::Message {
+                                        peer,
                                         message:
                                         request_response::Message::Request {
                                             request, channel, ..
                                         },
-                                        ..
-                                    }) => self.handle_inbound_request(request, channel),
+                                    }) => self.handle_inbound_request(peer, request, channel),
 
             BehaviourEvent::ReqResp(request_response::Event::Message {
                                         message:
@@ -341,93 +1273,693 @@ impl<E: EventHandler> Server<E> {
                                         ..
                                     }) => self.handle_outbound_failure(request_id, error),
 
+            BehaviourEvent::Stream(request_response::Event::Message {
+                                       peer,
+                                       message:
+                                       request_response::Message::Request {
+                                           request, channel, ..
+                                       },
+                                   }) => self.handle_inbound_stream(peer, request, channel),
+
+            BehaviourEvent::Stream(request_response::Event::Message {
+                                       message:
+                                       request_response::Message::Response {
+                                           request_id,
+                                           response,
+                                       },
+                                       ..
+                                   }) => {
+                if let Some(responder) = self.pending_stream_requests.remove(&request_id) {
+                    let _ = responder.send(Ok(response));
+                }
+            }
+
+            BehaviourEvent::Stream(request_response::Event::OutboundFailure {
+                                       request_id,
+                                       error,
+                                       ..
+                                   }) => {
+                warn!("❗ Outbound stream request failed: {:?}", error);
+                if let Some(responder) = self.pending_stream_requests.remove(&request_id) {
+                    let _ = responder.send(Err(error));
+                }
+            }
+
             BehaviourEvent::Pubsub(gossipsub::Event::Message {
-                                       propagation_source: _,
-                                       message_id: _,
+                                       propagation_source,
+                                       message_id,
                                        message,
-                                   }) => self.handle_inbound_broadcast(message),
+                                   }) => self.handle_inbound_broadcast(propagation_source, message_id, message),
 
             _ => {}
         }
     }
 
-    // Inbound requests are handled by the `EventHandler` which is provided by the application layer.
-    fn handle_inbound_request(&mut self, request: Vec<u8>, ch: ResponseChannel<ResponseType>) {
-        if let Some(handler) = self.event_handler.get() {
-            let response = handler.handle_inbound_request(request).map_err(|_| ());
+    // Inbound requests are handled by the `RequestHandler` of their protocol, or by the `EventHandler`
+    // which is provided by the application layer.
+    fn handle_inbound_request(&mut self, peer: PeerId, request: Request, ch: ResponseChannel<ResponseType>) {
+        self.metrics.record_inbound_request(&request.protocol);
+        self.bandwidth
+            .record_req_resp(&request.protocol, request.payload.len() as u64, 0);
+        let settings = match self.req_resp_protocols.get_mut(&request.protocol) {
+            Some(settings) if settings.support.inbound() => settings,
+            _ => {
+                warn!("❗ Received request on unsupported protocol: {}", request.protocol);
+                self.network_service
+                    .behaviour_mut()
+                    .send_response(ch, Err(ResponseError::UnsupportedProtocol));
+                return;
+            }
+        };
+
+        // A retry of a request which was already handled gets the same response.
+        if let Some(key) = &request.idempotency_key {
+            if let Some(response) = self.response_cache.get(&peer, &request.protocol, key) {
+                debug!("☕ Replaying response to request {} from {}", key, peer);
+                self.network_service.behaviour_mut().send_response(ch, response);
+                return;
+            }
+        }
+
+        if !settings.rate_limiter.check(&peer) {
+            warn!("❗ Throttled inbound request from {}", peer);
+            if let Some(handler) = self.event_handler.get() {
+                handler.handle_throttled_peer(&peer);
+            }
+            self.network_service
+                .behaviour_mut()
+                .send_response(ch, Err(ResponseError::Throttled));
+            return;
+        }
+
+        let ctx = RequestContext {
+            peer_id: peer,
+            protocol: request.protocol,
+            version: request.version,
+            idempotency_key: request.idempotency_key,
+        };
+        let started = time::Instant::now();
+        let response = if ctx.protocol == BROADCAST_REPLY_PROTOCOL {
+            Some(self.handle_broadcast_reply(peer, request.payload))
+        } else {
+            match self.request_handlers.get(&ctx.protocol) {
+                Some(handler) => Some(handler.handle_request(&ctx, request.payload)),
+                None => self
+                    .event_handler
+                    .get()
+                    .map(|handler| handler.handle_request(&ctx, request.payload)),
+            }
+        };
+        self.metrics.record_handler_latency(&ctx.protocol, started.elapsed());
+        if let Some(response) = response {
+            let response = response.map_err(|err| match err {
+                P2pError::UnsupportedProtocol(_) => ResponseError::UnsupportedProtocol,
+                _ => ResponseError::Rejected,
+            });
+            if let Ok(payload) = &response {
+                self.bandwidth
+                    .record_req_resp(&ctx.protocol, 0, payload.len() as u64);
+            }
+            if let Some(key) = ctx.idempotency_key {
+                self.response_cache.insert(peer, ctx.protocol, key, response.clone());
+            }
             self.network_service
                 .behaviour_mut()
                 .send_response(ch, response);
         }
     }
 
-    // Store the request_id with the responder so that we can send the response later.
+    // Keep the request until it gets a response or fails for good, since it may be retried.
     fn handle_outbound_request(
         &mut self,
+        id: u64,
         target: PeerId,
-        request: Vec<u8>,
-        responder: oneshot::Sender<ResponseType>,
+        request: Request,
+        options: RequestOptions,
+        responder: Responder,
     ) {
+        let supported = matches!(
+            self.req_resp_protocols.get(&request.protocol),
+            Some(settings) if settings.support.outbound()
+        );
+        let pending = PendingRequest {
+            target,
+            request,
+            options,
+            retries: 0,
+            attempt: None,
+            deadline: time::Instant::now(),
+            responder,
+        };
+        if !supported {
+            warn!("❗ Sending request on unsupported protocol: {}", pending.request.protocol);
+            self.respond(pending, Err(ResponseError::UnsupportedProtocol));
+            return;
+        }
+
+        self.pending_outbound_requests.insert(id, pending);
+        self.send_attempt(id);
+    }
+
+    // Fan the request out to every target, the responses are gathered until the quorum is met.
+    fn handle_request_many(
+        &mut self,
+        id: u64,
+        targets: Vec<(u64, PeerId)>,
+        request: Request,
+        quorum: Quorum,
+        timeout: Duration,
+        responder: oneshot::Sender<Result<HashMap<PeerId, Vec<u8>>, P2pError>>,
+    ) {
+        // The libp2p behaviour would time the requests out before the `Server` does.
+        let max_timeout = self.max_request_timeout();
+        if timeout > max_timeout {
+            let _ = responder.send(Err(P2pError::RequestTimeoutTooLong(timeout, max_timeout)));
+            return;
+        }
+        self.pending_gathers.insert(
+            id,
+            PendingGather {
+                required: quorum.required(targets.len()),
+                remaining: targets.len(),
+                requests: targets.iter().map(|(request_id, _)| *request_id).collect(),
+                responses: HashMap::new(),
+                responder,
+            },
+        );
+        self.resolve_gather(id);
+
+        let options = RequestOptions {
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+        for (request_id, target) in targets {
+            if !self.pending_gathers.contains_key(&id) {
+                break;
+            }
+            self.handle_outbound_request(request_id, target, request.clone(), options.clone(), Responder::Gather(id));
+        }
+    }
+
+    fn respond(&mut self, pending: PendingRequest, response: ResponseType) {
+        match pending.responder {
+            Responder::Client(responder) => {
+                let _ = responder.send(response);
+            }
+            Responder::Gather(id) => {
+                if let Some(gather) = self.pending_gathers.get_mut(&id) {
+                    gather.remaining = gather.remaining.saturating_sub(1);
+                    if let Ok(response) = response {
+                        gather.responses.insert(pending.target, response);
+                    }
+                    self.resolve_gather(id);
+                }
+            }
+            Responder::Discard => {}
+        }
+    }
+
+    // Resolve a scatter-gather request once its quorum is met, or can't be anymore.
+    fn resolve_gather(&mut self, id: u64) {
+        let reached = match self.pending_gathers.get(&id) {
+            Some(gather) if gather.responses.len() >= gather.required => true,
+            Some(gather) if gather.responses.len() + gather.remaining < gather.required => false,
+            _ => return,
+        };
+
+        if let Some(gather) = self.pending_gathers.remove(&id) {
+            for request_id in &gather.requests {
+                self.cancel_request(*request_id);
+            }
+            let result = if reached {
+                Ok(gather.responses)
+            } else {
+                warn!("❗ Quorum not reached: {} of {} responses", gather.responses.len(), gather.required);
+                Err(P2pError::QuorumNotReached {
+                    responses: gather.responses,
+                    required: gather.required,
+                })
+            };
+            let _ = gather.responder.send(result);
+        }
+    }
+
+    // The request timeout of the libp2p behaviour, which is the longest of all protocols.
+    fn max_request_timeout(&self) -> Duration {
+        self.req_resp_protocols
+            .values()
+            .map(|settings| settings.request_timeout)
+            .max()
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    // Send an attempt of a pending request, and store its request_id so that we can match the response.
+    fn send_attempt(&mut self, id: u64) {
+        let pending = match self.pending_outbound_requests.get_mut(&id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let request_timeout = pending
+            .options
+            .timeout
+            .or_else(|| {
+                self.req_resp_protocols
+                    .get(&pending.request.protocol)
+                    .map(|settings| settings.request_timeout)
+            })
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+
+        self.metrics.record_outbound_request(&pending.request.protocol);
+        self.bandwidth
+            .record_req_resp(&pending.request.protocol, 0, pending.request.payload.len() as u64);
         let req_id = self
             .network_service
             .behaviour_mut()
-            .send_request(&target, request);
-        self.pending_outbound_requests.insert(req_id, responder);
+            .send_request(&pending.target, pending.request.clone());
+        pending.attempt = Some(req_id);
+        pending.deadline = time::Instant::now() + request_timeout;
+        self.outbound_attempts.insert(req_id, id);
+    }
+
+    // A response to a cancelled request is ignored, as its attempt is forgotten.
+    fn cancel_request(&mut self, id: u64) {
+        if let Some(pending) = self.pending_outbound_requests.remove(&id) {
+            debug!("☕ Cancelled outbound request {}", id);
+            if let Some(attempt) = pending.attempt {
+                self.outbound_attempts.remove(&attempt);
+            }
+        }
     }
 
-    // An outbound request failed, notify the application layer.
     fn handle_outbound_failure(&mut self, request_id: RequestId, error: OutboundFailure) {
-        if let Some(responder) = self.pending_outbound_requests.remove(&request_id) {
+        match self.outbound_attempts.remove(&request_id) {
+            Some(id) => self.fail_attempt(id, error),
+            // The request may have already timed out on our side, or been cancelled.
+            None => debug!("☕ Received failure for unknown request: {}", request_id),
+        }
+    }
+
+    // An attempt failed, retry the request if the failure is transient, or notify the application layer.
+    fn fail_attempt(&mut self, id: u64, error: OutboundFailure) {
+        let pending = match self.pending_outbound_requests.get_mut(&id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        pending.attempt = None;
+        self.metrics.record_outbound_failure(&pending.request.protocol, &error);
+
+        let transient = match error {
+            OutboundFailure::DialFailure | OutboundFailure::ConnectionClosed => true,
+            OutboundFailure::Timeout => pending.options.idempotency_key.is_some(),
+            _ => false,
+        };
+        if transient && pending.retries < pending.options.retries {
+            let backoff = pending
+                .options
+                .backoff
+                .saturating_mul(1 << pending.retries.min(16));
+            pending.retries += 1;
+            pending.deadline = time::Instant::now() + backoff;
+            warn!("❗ Outbound request failed: {:?}, retrying in {:?}", error, backoff);
+            return;
+        }
+
+        if let Some(pending) = self.pending_outbound_requests.remove(&id) {
             if let Some(handler) = self.event_handler.get() {
                 handler.handle_outbound_failure(error.clone());
             }
             error!("❌ Outbound request failed: {:?}", error);
-            let _ = responder.send(Err(()));
-        } else {
-            warn!("❗ Received failure for unknown request: {}", request_id);
-            debug_assert!(false);
+            self.respond(pending, Err(ResponseError::Rejected));
         }
     }
 
     // An inbound response was received, notify the application layer.
     fn handle_inbound_response(&mut self, request_id: RequestId, response: ResponseType) {
-        if let Some(responder) = self.pending_outbound_requests.remove(&request_id) {
+        let pending = self
+            .outbound_attempts
+            .remove(&request_id)
+            .and_then(|id| self.pending_outbound_requests.remove(&id));
+        if let Some(pending) = pending {
+            if let Ok(payload) = &response {
+                self.bandwidth
+                    .record_req_resp(&pending.request.protocol, payload.len() as u64, 0);
+            }
             if let Some(handler) = self.event_handler.get() {
                 handler.handle_inbound_response(response.clone());
             }
-            let _ = responder.send(response);
+            self.respond(pending, response);
         } else {
-            warn!("❗ Received response for unknown request: {}", request_id);
-            debug_assert!(false);
+            // The request may have already timed out on our side, or been cancelled.
+            debug!("☕ Received response for unknown request: {}", request_id);
         }
     }
 
-    // Inbound broadcasts are handled by the `EventHandler` which is provided by the application layer.
-    fn handle_inbound_broadcast(&mut self, message: gossipsub::Message) {
-        if let Some(handler) = self.event_handler.get() {
-            let topic_hash = message.topic;
-            match self.get_topic(&topic_hash) {
-                Some(topic) => handler.handle_inbound_broadcast(topic, message.data),
-                None => {
-                    warn!("❗ Received broadcast for unknown topic: {:?}", topic_hash);
-                    debug_assert!(false);
+    // Attempts may have a shorter timeout than the one of the underlying behaviour,
+    // which is the longest timeout of all protocols.
+    // Requests waiting for their backoff to elapse are sent again.
+    fn expire_requests(&mut self) {
+        let now = time::Instant::now();
+        let expired = self
+            .pending_outbound_requests
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, pending)| (*id, pending.attempt))
+            .collect::<Vec<_>>();
+        self.pending_broadcast_requests
+            .retain(|_, pending| pending.deadline > now);
+        for (id, attempt) in expired {
+            match attempt {
+                Some(attempt) => {
+                    self.outbound_attempts.remove(&attempt);
+                    self.fail_attempt(id, OutboundFailure::Timeout);
+                }
+                None => self.send_attempt(id),
+            }
+        }
+    }
+
+    fn next_request_deadline(&self) -> Option<time::Instant> {
+        self.pending_outbound_requests
+            .values()
+            .map(|pending| pending.deadline)
+            .chain(self.pending_broadcast_requests.values().map(|pending| pending.deadline))
+            .min()
+    }
+
+    // Publish the request on the companion topic, the replies are forwarded to the `Client` until the deadline.
+    fn handle_outbound_broadcast_request(
+        &mut self,
+        id: u64,
+        topic: String,
+        request: Vec<u8>,
+        timeout: Duration,
+        replies: UnboundedSender<(PeerId, Vec<u8>)>,
+    ) -> Result<(), P2pError> {
+        let envelope = BroadcastRequest {
+            id,
+            reply_to: self.local_peer_id,
+            payload: request,
+        };
+        let (topic, message) = (request_topic(&topic), envelope.encode());
+        let size = message.len() as u64;
+        self.network_service
+            .behaviour_mut()
+            .broadcast(topic.clone(), message)?;
+        self.bandwidth.record_pubsub(&topic, 0, size);
+
+        self.pending_broadcast_requests.insert(
+            id,
+            PendingBroadcastRequest {
+                deadline: time::Instant::now() + timeout,
+                replies,
+            },
+        );
+        Ok(())
+    }
+
+    // Forward a reply to the `Client` awaiting the broadcast request it answers.
+    fn handle_broadcast_reply(&mut self, peer: PeerId, reply: Vec<u8>) -> Result<Vec<u8>, P2pError> {
+        let (id, reply) = decode_reply(&reply)?;
+        let delivered = match self.pending_broadcast_requests.get(&id) {
+            Some(pending) => pending.replies.send((peer, reply)).is_ok(),
+            // The broadcast request may have already timed out.
+            None => return Err(P2pError::RequestRejected),
+        };
+        if !delivered {
+            // The `Client` stopped reading the replies.
+            self.pending_broadcast_requests.remove(&id);
+            return Err(P2pError::RequestCancelled);
+        }
+        Ok(Vec::new())
+    }
+
+    // Let the `EventHandler` answer a broadcast request, and send its reply to the requester.
+    fn reply_to_broadcast_request(&mut self, topic: &str, request: BroadcastRequest) {
+        let reply = self
+            .event_handler
+            .get()
+            .and_then(|handler| handler.handle_broadcast_request(topic, &request.reply_to, request.payload));
+        if let Some(reply) = reply {
+            let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+            self.handle_outbound_request(
+                id,
+                request.reply_to,
+                Request {
+                    protocol: BROADCAST_REPLY_PROTOCOL.to_string(),
+                    version: ProtocolVersion::latest(),
+                    idempotency_key: None,
+                    payload: encode_reply(request.id, &reply),
+                },
+                RequestOptions::default(),
+                Responder::Discard,
+            );
+        }
+    }
+
+    // Inbound stream chunks are written by `IncomingStreams`, completed streams are handed to a waiting `Client`.
+    fn handle_inbound_stream(&mut self, peer: PeerId, request: StreamRequest, ch: ResponseChannel<StreamResponse>) {
+        let id = match &request {
+            StreamRequest::Open { id, .. } | StreamRequest::Chunk { id, .. } => id.clone(),
+        };
+        // The file I/O blocks, so it runs off the event loop, which answers once it is done.
+        let job = self.incoming_streams.handle(peer, request);
+        let sender = self.stream_job_sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let outcome = job();
+            let _ = sender.send(StreamJobResult { peer, id, channel: ch, outcome });
+        });
+    }
+
+    fn handle_stream_job_result(&mut self, result: StreamJobResult) {
+        let StreamJobResult { peer, id, channel, outcome } = result;
+        self.incoming_streams.settle(&peer, &id, &outcome.response);
+        if let StreamResponse::Error(reason) = &outcome.response {
+            warn!("❗ Inbound stream from {} failed: {}", peer, reason);
+        }
+        self.network_service
+            .behaviour_mut()
+            .send_stream_response(channel, outcome.response);
+
+        if let (Some(handler), Some((received, size))) = (self.event_handler.get(), outcome.progress) {
+            handler.handle_stream_progress(&peer, &id, received, size);
+        }
+        if let Some(stream) = outcome.completed {
+            info!("📣 Received stream {} ({} bytes) from {}", stream.id, stream.size, peer);
+            self.deliver_stream(stream);
+        }
+    }
+
+    fn handle_receive_stream(&mut self, responder: oneshot::Sender<ReceivedStream>) {
+        match self.received_streams.pop_front() {
+            Some(stream) => {
+                if let Err(stream) = responder.send(stream) {
+                    self.received_streams.push_front(stream);
+                }
+            }
+            None => self.stream_waiters.push_back(responder),
+        }
+    }
+
+    // Hand the stream to the first `Client` still waiting, or keep it until one asks.
+    fn deliver_stream(&mut self, mut stream: ReceivedStream) {
+        while let Some(waiter) = self.stream_waiters.pop_front() {
+            match waiter.send(stream) {
+                Ok(()) => return,
+                Err(returned) => stream = returned,
+            }
+        }
+        if self.received_streams.len() >= MAX_RECEIVED_STREAMS {
+            if let Some(dropped) = self.received_streams.pop_front() {
+                warn!("❗ Dropped received stream {} which no client received", dropped.id);
+            }
+        }
+        self.received_streams.push_back(stream);
+    }
+
+    // Inbound broadcasts are validated and then handled by the `EventHandler` which is provided by the application layer.
+    fn handle_inbound_broadcast(
+        &mut self,
+        propagation_source: PeerId,
+        message_id: MessageId,
+        message: gossipsub::Message,
+    ) {
+        let topic_hash = message.topic;
+        let topic = match self.get_topic(&topic_hash) {
+            Some(topic) => topic,
+            None => {
+                warn!("❗ Received broadcast for unknown topic: {:?}", topic_hash);
+                debug_assert!(false);
+                self.network_service.behaviour_mut().report_message_validation_result(
+                    &message_id,
+                    &propagation_source,
+                    MessageAcceptance::Ignore,
+                );
+                return;
+            }
+        };
+
+        self.bandwidth.record_pubsub(&topic, message.data.len() as u64, 0);
+
+        // Broadcast requests are subject to the ACL of their topic.
+        let request_topic = base_topic(&topic).map(ToString::to_string);
+        let mut broadcast_request = None;
+        let mut key_handover = None;
+        let acceptance = if !self.is_allowed_publisher(request_topic.as_deref().unwrap_or(&topic), message.source.as_ref()) {
+            warn!("❗ Rejected broadcast on {} from unauthorized source {:?}", topic, message.source);
+            if let Some(handler) = self.event_handler.get() {
+                handler.handle_unauthorized_broadcast(&topic, message.source.as_ref());
+            }
+            MessageAcceptance::Reject
+        } else if request_topic.is_some() {
+            // Replies go to the peer named in the envelope, so it must be the one which signed it.
+            match BroadcastRequest::decode(&message.data) {
+                Ok(request) if Some(&request.reply_to) == message.source.as_ref() => {
+                    let acceptance = match self.event_handler.get() {
+                        Some(handler) => handler.validate_broadcast(&topic, message.source.as_ref(), &request.payload),
+                        None => MessageAcceptance::Accept,
+                    };
+                    if matches!(acceptance, MessageAcceptance::Accept) {
+                        broadcast_request = Some(request);
+                    }
+                    acceptance
+                }
+                _ => MessageAcceptance::Reject,
+            }
+        } else if topic == KEY_HANDOVER_TOPIC {
+            // Only the old key may announce its handover, and only recently so it can't be replayed.
+            match KeyHandover::decode(&message.data) {
+                Ok(handover) if handover.verify() && Some(handover.old_peer_id()) == message.source => {
+                    if handover.is_fresh() {
+                        key_handover = Some(handover);
+                        MessageAcceptance::Accept
+                    } else {
+                        MessageAcceptance::Ignore
+                    }
+                }
+                _ => MessageAcceptance::Reject,
+            }
+        } else {
+            match self.event_handler.get() {
+                Some(handler) => handler.validate_broadcast(&topic, message.source.as_ref(), &message.data),
+                None => MessageAcceptance::Accept,
+            }
+        };
+        let accepted = matches!(acceptance, MessageAcceptance::Accept);
+        if !accepted {
+            debug!("☕ Broadcast {} on {} not accepted: {:?}", message_id, topic, acceptance);
+        }
+        self.network_service
+            .behaviour_mut()
+            .report_message_validation_result(&message_id, &propagation_source, acceptance);
+
+        if accepted {
+            self.metrics.record_broadcast(&topic, Direction::Inbound);
+            match (request_topic, broadcast_request, key_handover) {
+                (Some(request_topic), Some(request), _) => self.reply_to_broadcast_request(&request_topic, request),
+                (_, _, Some(handover)) => self.handle_key_handover(handover),
+                _ => {
+                    if let Some(handler) = self.event_handler.get() {
+                        handler.handle_inbound_broadcast(topic, message.data);
+                    }
                 }
             }
         }
     }
 
+    // Record the first handover of a peer, and move its state to its new `PeerId` if the handler follows it.
+    // A later handover to another key means the old key is compromised, it's reported and never followed.
+    // Only the handovers of peers this node knows are recorded, so that anyone can't grow the records.
+    fn handle_key_handover(&mut self, handover: KeyHandover) {
+        let (old, new) = (handover.old_peer_id(), handover.new_peer_id());
+        if !self.is_known_peer(&old) {
+            debug!("☕ Ignored the key handover of unknown peer {}", old);
+            return;
+        }
+        match self.key_handovers.get(&old) {
+            Some(first) if first.new_key == handover.new_key => return,
+            Some(first) => {
+                warn!("❗ Peer {} handed over its identity to {} after {}", old, new, first.new_peer_id());
+                if let Some(handler) = self.event_handler.get() {
+                    handler.handle_key_handover_conflict(first, &handover);
+                }
+                return;
+            }
+            None => {}
+        }
+        info!("📣 Peer {} handed over its identity to {}", old, new);
+        let followed = self
+            .event_handler
+            .get()
+            .is_some_and(|handler| handler.handle_key_handover(&handover));
+        if let Some(record) = self.key_handovers.insert(handover) {
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = record() {
+                    warn!("❗ Failed to record the key handover of {}: {}", old, err);
+                }
+            });
+        }
+        if !followed {
+            return;
+        }
+
+        for acl in self.topic_acls.values_mut() {
+            if let TopicAcl::Peers(peers) = acl {
+                if peers.remove(&old) {
+                    peers.insert(new);
+                }
+            }
+        }
+
+        let behaviour = self.network_service.behaviour_mut();
+        if let Some(addrs) = behaviour.known_peers().remove(&old) {
+            for addr in addrs {
+                behaviour.add_address(&new, addr);
+            }
+            behaviour.remove_peer(&old);
+        }
+
+        let boot_node = self
+            .boot_node
+            .as_ref()
+            .filter(|boot_node| boot_node.peer_id() == old)
+            .map(|boot_node| PeerIdWithMultiaddr(new, boot_node.address()));
+        if boot_node.is_some() {
+            self.boot_node = boot_node;
+        }
+    }
+
+    // Whether the peer is connected, in the routing table, listed in a topic ACL or the boot node.
+    fn is_known_peer(&mut self, peer: &PeerId) -> bool {
+        self.network_service.is_connected(peer)
+            || self.network_service.behaviour_mut().known_peers().contains_key(peer)
+            || self
+                .topic_acls
+                .values()
+                .any(|acl| matches!(acl, TopicAcl::Peers(peers) if peers.contains(peer)))
+            || self.boot_node.as_ref().is_some_and(|boot_node| boot_node.peer_id() == *peer)
+    }
+
+    // Generate a new keypair and sign the handover to it, the client broadcasts it once the keypair is saved.
+    fn new_key_handover(&self) -> Result<(Keypair, KeyHandover), P2pError> {
+        let key_type = keys::key_type_of(&self.local_key)
+            .ok_or_else(|| P2pError::InvalidConfig("unsupported local key type".to_string()))?;
+        let new_key = keys::generate(key_type);
+        let handover = KeyHandover::new(&self.local_key, &new_key)?;
+        Ok((new_key, handover))
+    }
+
     // Broadcast a message to all peers subscribed to the given topic.
-    fn handle_outbound_broadcast(&mut self, topic: String, message: Vec<u8>) {
-        let _ = self
-            .network_service
+    fn handle_outbound_broadcast(&mut self, topic: String, message: Vec<u8>) -> Result<(), P2pError> {
+        let (metrics_topic, size) = (topic.clone(), message.len() as u64);
+        self.network_service
             .behaviour_mut()
-            .broadcast(topic, message);
+            .broadcast(topic, message)?;
+        self.metrics.record_broadcast(&metrics_topic, Direction::Outbound);
+        self.bandwidth.record_pubsub(&metrics_topic, 0, size);
+        Ok(())
     }
 
     fn add_addresses(&mut self, peer_id: &PeerId, addresses: Vec<Multiaddr>) {
-        if let Some(handler) = self.event_handler.get() {
-            handler.handle_identify(peer_id, addresses.clone());
-        }
         for addr in addresses.into_iter().unique() {
             self.network_service
                 .behaviour_mut()
@@ -442,13 +1974,97 @@ impl<E: EventHandler> Server<E> {
         self.network_service.behaviour_mut().remove_peer(peer);
     }
     
+    fn subscribe(&mut self, topic: String) -> Result<(), P2pError> {
+        if self.network_service.behaviour_mut().subscribe(&topic)? {
+            self.pubsub_topics.push(topic);
+        }
+        Ok(())
+    }
+
     fn get_status(&mut self) -> NodeStatus {
         let known_peers = self.network_service.behaviour_mut().known_peers();
+        let bandwidth = self.bandwidth.total();
+        let behaviour = self.network_service.behaviour();
+        let mesh_peers = self
+            .pubsub_topics
+            .iter()
+            .map(|topic| (topic.clone(), behaviour.mesh_peers(topic)))
+            .collect();
         NodeStatus {
             local_peer_id: self.local_peer_id.to_base58(),
             listened_addresses: self.listened_addresses.clone(),
+            external_addresses: self.network_service.external_addresses().cloned().collect(),
+            candidate_addresses: self.external_addrs.candidates(),
             known_peers_count: known_peers.len(),
             known_peers,
+            connections: self.connections.values().cloned().collect(),
+            ping_rtts: self.ping_rtts.clone(),
+            peer_infos: self.peer_infos.clone(),
+            mesh_peers,
+            pending_requests: self.pending_outbound_requests.len(),
+            bytes_in: bandwidth.inbound,
+            bytes_out: bandwidth.outbound,
+        }
+    }
+
+    /// Record the address a peer observed us at, and advertise the addresses confirmed by enough peers.
+    fn observe_addr(&mut self, peer_id: PeerId, observed: &Multiaddr) {
+        let confirmed = self
+            .external_addrs
+            .observe(peer_id, observed, &self.listened_addresses);
+        for addr in confirmed {
+            self.add_external_address(addr);
+        }
+    }
+
+    // Return the addresses of the new listeners whose collection window is over.
+    fn return_listen_addrs(&mut self) {
+        let now = time::Instant::now();
+        let ready = self
+            .pending_listeners
+            .iter()
+            .filter(|(_, listener)| listener.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ready {
+            if let Some(listener) = self.pending_listeners.remove(&id) {
+                let _ = listener.responder.send(Ok((id, listener.addrs)));
+            }
+        }
+    }
+
+    fn expire_external_addrs(&mut self) {
+        for addr in self.external_addrs.expire() {
+            // The address may also be configured, or a public listen address.
+            let permanent = self.external_addrs.public_addrs().contains(&addr)
+                || (self.listened_addresses.contains(&addr) && self.external_addrs.is_external_listen_addr(&addr));
+            if !permanent {
+                info!("📣 External address expired: {}", addr);
+                self.remove_external_address(&addr);
+            }
+        }
+    }
+
+    /// Advertise the external address, and notify the handler as the swarm reports no event for it.
+    fn add_external_address(&mut self, addr: Multiaddr) {
+        if self.network_service.external_addresses().any(|external| *external == addr) {
+            return;
+        }
+        info!("📣 External address confirmed: {}", addr);
+        if let Some(handler) = self.event_handler.get() {
+            handler.handle_new_external_addr(&addr);
+        }
+        self.network_service.add_external_address(addr);
+    }
+
+    /// Stop advertising the external address, and notify the handler as the swarm reports no event for it.
+    fn remove_external_address(&mut self, addr: &Multiaddr) {
+        if !self.network_service.external_addresses().any(|external| external == addr) {
+            return;
+        }
+        self.network_service.remove_external_address(addr);
+        if let Some(handler) = self.event_handler.get() {
+            handler.handle_expired_external_addr(addr);
         }
     }
 
@@ -460,6 +2076,15 @@ impl<E: EventHandler> Server<E> {
             .collect();
     }
 
+    /// Whether `source` may publish on `topic`, messages without a source are only allowed on open topics.
+    fn is_allowed_publisher(&self, topic: &str, source: Option<&PeerId>) -> bool {
+        match (self.topic_acls.get(topic), source) {
+            (None, _) => true,
+            (Some(acl), Some(source)) => acl.is_allowed(source),
+            (Some(_), None) => false,
+        }
+    }
+
     /// Returns the topic name for the given topic hash.
     fn get_topic(&self, topic_hash: &TopicHash) -> Option<String> {
         for t in &self.pubsub_topics {
@@ -472,16 +2097,22 @@ impl<E: EventHandler> Server<E> {
         None
     }
 
-    fn handle_listener_closed(&mut self, reason: io::Result<()>, addresses: Vec<Multiaddr>) {
+    fn handle_listener_closed(&mut self, listener_id: ListenerId, reason: io::Result<()>, addresses: Vec<Multiaddr>) {
         if let Some(handler) = self.event_handler.get() {
             handler.handle_listener_closed(addresses.clone());
         }
+        for addr in &addresses {
+            if self.external_addrs.is_external_listen_addr(addr) {
+                self.remove_external_address(addr);
+            }
+        }
+        self.update_listened_addresses();
         let addrs = addresses
             .into_iter()
             .map(|a| a.to_string())
             .collect::<Vec<_>>()
             .join(", ");
-        match reason {
+        match &reason {
             Ok(()) => {
                 info!("📣 Listener ({}) closed gracefully", addrs)
             }
@@ -489,15 +2120,110 @@ impl<E: EventHandler> Server<E> {
                 error!("❌ Listener ({}) closed: {}", addrs, e)
             }
         }
+        // The listener closed before its addresses were returned.
+        if let Some(listener) = self.pending_listeners.remove(&listener_id) {
+            let result = match reason {
+                Err(err) if listener.addrs.is_empty() => Err(err.into()),
+                Ok(()) if listener.addrs.is_empty() => Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "the listener closed before it was bound",
+                )
+                .into()),
+                _ => Ok((listener_id, listener.addrs)),
+            };
+            let _ = listener.responder.send(result);
+        }
+    }
+}
+
+/// Sleep until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
     }
 }
 
 /// The node status, for debugging.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct NodeStatus {
     pub local_peer_id: String,
     pub listened_addresses: Vec<Multiaddr>,
+    /// The confirmed external addresses, advertised to peers.
+    pub external_addresses: Vec<Multiaddr>,
+    /// The addresses observed by peers, not confirmed by enough of them yet.
+    pub candidate_addresses: Vec<Multiaddr>,
     // store relay addr?
     pub known_peers_count: usize,
     pub known_peers: HashMap<PeerId, Vec<Multiaddr>>,
+    /// The established connections.
+    pub connections: Vec<ConnectionInfo>,
+    /// The round-trip time of the last ping to each connected peer.
+    pub ping_rtts: HashMap<PeerId, Duration>,
+    /// The identify information of the connected peers.
+    pub peer_infos: HashMap<PeerId, PeerInfo>,
+    /// The pub/sub mesh peers of each subscribed topic.
+    pub mesh_peers: HashMap<String, Vec<PeerId>>,
+    /// The number of outbound requests awaiting a response.
+    pub pending_requests: usize,
+    /// The total bytes received over all connections.
+    pub bytes_in: u64,
+    /// The total bytes sent over all connections.
+    pub bytes_out: u64,
+}
+
+/// The direction of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ConnectionDirection {
+    /// The remote peer dialed us.
+    Inbound,
+    /// We dialed the remote peer.
+    Outbound,
+}
+
+/// An established connection.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub peer_id: PeerId,
+    pub direction: ConnectionDirection,
+    pub remote_address: Multiaddr,
+}
+
+impl ConnectionInfo {
+    fn new(peer_id: PeerId, endpoint: &ConnectedPoint) -> Self {
+        Self {
+            peer_id,
+            direction: if endpoint.is_dialer() {
+                ConnectionDirection::Outbound
+            } else {
+                ConnectionDirection::Inbound
+            },
+            remote_address: endpoint.get_remote_address().clone(),
+        }
+    }
+}
+
+/// The information a peer sent with the identify protocol.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerInfo {
+    pub protocol_version: String,
+    pub agent_version: String,
+    /// The protocols the peer supports.
+    pub protocols: Vec<String>,
+    /// The addresses the peer listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Our address, as observed by the peer.
+    pub observed_addr: Multiaddr,
+}
+
+impl From<identify::Info> for PeerInfo {
+    fn from(info: identify::Info) -> Self {
+        Self {
+            protocol_version: info.protocol_version,
+            agent_version: info.agent_version,
+            protocols: info.protocols.iter().map(ToString::to_string).collect(),
+            listen_addrs: info.listen_addrs,
+            observed_addr: info.observed_addr,
+        }
+    }
 }
\ No newline at end of file
@@ -1,17 +1,34 @@
+use std::sync::Arc;
+
 use libp2p::{
     core::{muxing::StreamMuxerBox, transport::Boxed, upgrade::Version},
-    identity, noise, tcp, yamux, PeerId, Transport
+    futures::future::Either,
+    identity, noise, quic, tcp, yamux, PeerId, Transport
 };
 
-/// Create a tokio-based TCP transport use noise for authenticated
+use crate::bandwidth::{BandwidthMeter, CountingMuxer};
+
+/// Create a tokio-based transport over QUIC, and over TCP using noise for authenticated
 /// encryption and Yamux for multiplexing of substreams on a TCP stream.
-pub fn build_transport(keypair: identity::Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
+/// The substreams of every connection are counted by the `meter`, whatever its transport.
+pub fn build_transport(keypair: identity::Keypair, meter: Arc<BandwidthMeter>) -> Boxed<(PeerId, StreamMuxerBox)> {
     let noise_config = noise::Config::new(&keypair).expect("failed to construct the noise config");
 
-    // potential support other transports for better nat traversal
-    tcp::tokio::Transport::default()
+    let tcp = tcp::tokio::Transport::default()
         .upgrade(Version::V1Lazy)
         .authenticate(noise_config)
-        .multiplex(yamux::Config::default())
+        .multiplex(yamux::Config::default());
+    // QUIC has its own encryption and multiplexing.
+    let quic = quic::tokio::Transport::new(quic::Config::new(&keypair));
+
+    quic.or_transport(tcp)
+        .map(move |output, _| {
+            let (peer, muxer) = match output {
+                Either::Left((peer, muxer)) => (peer, StreamMuxerBox::new(muxer)),
+                Either::Right((peer, muxer)) => (peer, StreamMuxerBox::new(muxer)),
+            };
+            let muxer = CountingMuxer::new(muxer, peer, meter.clone());
+            (peer, StreamMuxerBox::new(muxer))
+        })
         .boxed()
-}
\ No newline at end of file
+}